@@ -0,0 +1,107 @@
+//! Compares the route lookup tree against the lookup it replaced: routes as lists of prefix
+//! segments sorted by length, scanned linearly against the request path split on every request.
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pux::path::PathPattern;
+use pux::router::RouterBuilder;
+
+const HOST: &str = "api.m4rc3l.de";
+
+/// The former `Routes`, which only supported literal prefixes.
+struct Baseline(HashMap<String, Vec<(Vec<String>, usize)>>);
+
+impl Baseline {
+  fn insert(&mut self, host: String, path: Vec<String>, value: usize) {
+    let paths = self.0.entry(host).or_default();
+    paths.push((path, value));
+    paths.sort_by_key(|(path, _)| path.len());
+  }
+
+  fn find(&self, supplied_host: &str, supplied_path: &[&str]) -> Option<&usize> {
+    let paths = self.0.get(supplied_host)?;
+
+    for (path, value) in paths {
+      if starts_with(path, supplied_path) {
+        return Some(value);
+      }
+    }
+
+    None
+  }
+}
+
+fn starts_with(base: &[String], supplied: &[&str]) -> bool {
+  if base.len() > supplied.len() {
+    return false;
+  }
+
+  for (i, segment) in base.iter().enumerate() {
+    if supplied[i] != segment {
+      return false;
+    }
+  }
+
+  true
+}
+
+fn patterns() -> Vec<(String, bool)> {
+  let mut patterns = Vec::new();
+
+  for service in 0..50 {
+    patterns.push((format!("/service-{}", service), false));
+    patterns.push((format!("/service-{}/health", service), true));
+    patterns.push((format!("/service-{}/users/{{id}}", service), false));
+    patterns.push((format!("/service-{}/users/{{id}}/avatar", service), true));
+    patterns.push((format!("/service-{}/static/*", service), false));
+    patterns.push((format!("/service-{}/v1/items", service), false));
+  }
+
+  patterns
+}
+
+fn lookup(c: &mut Criterion) {
+  let paths = [
+    "/service-0/health",
+    "/service-25/users/42/avatar",
+    "/service-49/static/css/main.css",
+    "/service-49/v1/items/1337",
+    "/unknown/path",
+  ];
+
+  let mut builder = RouterBuilder::new();
+  let mut baseline = Baseline(HashMap::new());
+
+  for (i, (pattern, exact)) in patterns().iter().enumerate() {
+    builder.insert(HOST.to_string(), 0, PathPattern::parse(pattern, *exact), i);
+    // parameters and wildcards are compared literally, like any other segment before
+    baseline.insert(
+      HOST.to_string(),
+      pattern.split('/').map(str::to_string).collect(),
+      i,
+    );
+  }
+
+  let router = builder.build();
+
+  c.bench_function("tree", |b| {
+    b.iter(|| {
+      for path in paths {
+        black_box(router.find(black_box(HOST), black_box(path), |_| true));
+      }
+    })
+  });
+
+  c.bench_function("baseline", |b| {
+    b.iter(|| {
+      for path in paths {
+        let segments = black_box(path).split('/').collect::<Vec<&str>>();
+        black_box(baseline.find(black_box(HOST), &segments));
+      }
+    })
+  });
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
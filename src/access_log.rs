@@ -0,0 +1,531 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HOST, REFERER, USER_AGENT};
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
+use pin_project::{pin_project, pinned_drop};
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
+
+use crate::entrypoint::ConnInfo;
+use crate::error::PuxError;
+use crate::handler::{host_of, RequestId};
+use crate::template;
+use crate::upstream::UpstreamInfo;
+use crate::PuxResult;
+
+/// Lines waiting for the writer, further lines are dropped.
+const QUEUE_SIZE: usize = 8192;
+
+const MONTHS: [&str; 12] = [
+  "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub(crate) enum Format {
+  Common,
+  Combined,
+  Json,
+  /// Placeholders like `{status}` are replaced with the field of the same name.
+  Template(String),
+}
+
+/// Writes one line per response to stdout or a file, files can be reopened after rotation.
+pub(crate) struct AccessLog {
+  format: Format,
+  is_file: bool,
+  /// Lines are written by a dedicated thread, so a slow disk doesn't stall the runtime.
+  writer: SyncSender<Command>,
+  /// Fraction of successful responses that are logged.
+  sample_success: f64,
+  successes: AtomicU64,
+}
+
+enum Command {
+  Write(String),
+  Reopen,
+}
+
+/// Everything known about a request before it is handled, the header values share the request's
+/// memory and are only converted once the entry is written.
+pub(crate) struct Entry {
+  time: SystemTime,
+  start: Instant,
+  client_ip: IpAddr,
+  host: Option<HeaderValue>,
+  method: Method,
+  uri: Uri,
+  version: Version,
+  tls_version: Option<&'static str>,
+  request_id: Option<HeaderValue>,
+  referer: Option<HeaderValue>,
+  user_agent: Option<HeaderValue>,
+  status: StatusCode,
+  bytes: Option<u64>,
+  upstream: Option<UpstreamInfo>,
+}
+
+/// Counts the bytes of a body of unknown size and logs the entry once it is done.
+#[pin_project(PinnedDrop)]
+struct Counted {
+  #[pin]
+  body: Body,
+  bytes: u64,
+  pending: Option<(Arc<AccessLog>, Entry)>,
+}
+
+impl AccessLog {
+  pub(crate) fn new(format: Format, path: Option<String>, sample_success: f64) -> PuxResult<Self> {
+    let file =
+      match &path {
+        Some(path) => Some(open(path).map_err(|err| {
+          PuxError::Config(format!("Unable to open access log {}: {}", path, err))
+        })?),
+        None => None,
+      };
+    let is_file = file.is_some();
+
+    let (writer, commands) = sync_channel(QUEUE_SIZE);
+    thread::Builder::new()
+      .name("access-log".to_string())
+      .spawn(move || write_lines(commands, path, file))?;
+
+    Ok(Self {
+      format,
+      is_file,
+      writer,
+      sample_success,
+      successes: AtomicU64::new(0),
+    })
+  }
+
+  pub(crate) fn is_file(&self) -> bool {
+    self.is_file
+  }
+
+  /// Reopens the log file, so a file moved by logrotate is no longer written to.
+  fn reopen(&self) {
+    if self.writer.send(Command::Reopen).is_err() {
+      warn!("Unable to reopen access log, the writer stopped");
+    }
+  }
+
+  /// Logs the response, for bodies of unknown size once the body has been sent.
+  pub(crate) fn log(
+    self: &Arc<Self>,
+    mut entry: Entry,
+    mut resp: Response<Body>,
+  ) -> Response<Body> {
+    entry.status = resp.status();
+    if !self.sampled(entry.status) {
+      return resp;
+    }
+    entry.upstream = resp.extensions().get::<UpstreamInfo>().copied();
+
+    let size = HttpBody::size_hint(resp.body()).exact();
+    if size.is_some() || entry.method == Method::HEAD {
+      entry.bytes = size;
+      self.write(&entry);
+      return resp;
+    }
+
+    let body = std::mem::take(resp.body_mut());
+    *resp.body_mut() = Body::wrap_stream(Counted {
+      body,
+      bytes: 0,
+      pending: Some((self.clone(), entry)),
+    });
+    resp
+  }
+
+  /// Spreads the logged successes evenly instead of picking them at random.
+  fn sampled(&self, status: StatusCode) -> bool {
+    if !status.is_success() || self.sample_success >= 1.0 {
+      return true;
+    }
+
+    let count = self.successes.fetch_add(1, Ordering::Relaxed) as f64;
+    ((count + 1.0) * self.sample_success).floor() > (count * self.sample_success).floor()
+  }
+
+  fn write(&self, entry: &Entry) {
+    let mut line = match &self.format {
+      Format::Common => entry.common(),
+      Format::Combined => format!(
+        "{} \"{}\" \"{}\"",
+        entry.common(),
+        header_field(&entry.referer),
+        header_field(&entry.user_agent)
+      ),
+      Format::Json => entry.json(),
+      Format::Template(template) => entry.render(template),
+    };
+    line.push('\n');
+
+    match self.writer.try_send(Command::Write(line)) {
+      Ok(()) => {}
+      Err(TrySendError::Full(_)) => warn!("Dropping access log line, the writer is behind"),
+      Err(TrySendError::Disconnected(_)) => warn!("Dropping access log line, the writer stopped"),
+    }
+  }
+}
+
+fn write_lines(commands: Receiver<Command>, path: Option<String>, mut file: Option<File>) {
+  for command in commands {
+    match command {
+      Command::Write(line) => {
+        let result = match &mut file {
+          Some(file) => file.write_all(line.as_bytes()),
+          None => std::io::stdout().lock().write_all(line.as_bytes()),
+        };
+        if let Err(err) = result {
+          warn!("Unable to write access log: {}", err);
+        }
+      }
+      Command::Reopen => {
+        if let (Some(path), Some(file)) = (&path, &mut file) {
+          match open(path) {
+            Ok(reopened) => *file = reopened,
+            Err(err) => warn!("Unable to reopen access log {}: {}", path, err),
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Reopens all file logs on `SIGUSR1`.
+pub(crate) fn reopen_on_signal(logs: Vec<Arc<AccessLog>>) {
+  #[cfg(unix)]
+  tokio::spawn(async move {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+      .expect("failed to install signal handler");
+    while signal.recv().await.is_some() {
+      for log in &logs {
+        log.reopen();
+      }
+      info!("Reopened access logs");
+    }
+  });
+
+  #[cfg(not(unix))]
+  drop(logs);
+}
+
+impl Entry {
+  pub(crate) fn new(req: &Request<Body>, start: Instant) -> Self {
+    let conn_info = req.extensions().get::<ConnInfo>();
+    let header = |name| req.headers().get(name).cloned();
+
+    Self {
+      time: SystemTime::now(),
+      start,
+      client_ip: conn_info
+        .map(|conn_info| conn_info.peer_addr.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0])),
+      host: header(HOST),
+      method: req.method().clone(),
+      uri: req.uri().clone(),
+      version: req.version(),
+      tls_version: conn_info.and_then(|conn_info| conn_info.tls_version),
+      request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+      referer: header(REFERER),
+      user_agent: header(USER_AGENT),
+      status: StatusCode::OK,
+      bytes: None,
+      upstream: None,
+    }
+  }
+
+  fn host(&self) -> &str {
+    host_of(self.host.as_ref(), self.uri.authority()).unwrap_or("-")
+  }
+
+  fn path(&self) -> &str {
+    self
+      .uri
+      .path_and_query()
+      .map(|path_and_query| path_and_query.as_str())
+      .unwrap_or("/")
+  }
+
+  fn common(&self) -> String {
+    format!(
+      "{} - - [{}] \"{} {} {:?}\" {} {}",
+      self.client_ip,
+      clf_time(self.time),
+      self.method,
+      escape(self.path().as_bytes()),
+      self.version,
+      self.status.as_u16(),
+      self
+        .bytes
+        .map(|bytes| bytes.to_string())
+        .unwrap_or_else(|| "-".to_string())
+    )
+  }
+
+  fn json(&self) -> String {
+    let mut map = Map::new();
+    for name in FIELDS {
+      map.insert(name.to_string(), self.value(name));
+    }
+    Value::Object(map).to_string()
+  }
+
+  /// Values are escaped like in the combined format, templates may quote them.
+  fn render(&self, template: &str) -> String {
+    template::render(template, |name, line| {
+      if !FIELDS.contains(&name) {
+        return false;
+      }
+      match name {
+        "referer" => line.push_str(&header_field(&self.referer)),
+        "user_agent" => line.push_str(&header_field(&self.user_agent)),
+        _ => match self.value(name) {
+          Value::String(value) => line.push_str(&escape(value.as_bytes())),
+          Value::Null => line.push('-'),
+          value => line.push_str(&value.to_string()),
+        },
+      }
+      true
+    })
+  }
+
+  /// Durations are in milliseconds.
+  fn value(&self, name: &str) -> Value {
+    match name {
+      "time" => json!(rfc3339_time(self.time)),
+      "client_ip" => json!(self.client_ip.to_string()),
+      "host" => json!(self.host()),
+      "method" => json!(self.method.as_str()),
+      "path" => json!(self.path()),
+      "protocol" => json!(format!("{:?}", self.version)),
+      "status" => json!(self.status.as_u16()),
+      "bytes" => json!(self.bytes),
+      "duration" => json!(millis(self.start.elapsed())),
+      "upstream_addr" => json!(self.upstream.map(|upstream| upstream.addr.to_string())),
+      "upstream_latency" => json!(self.upstream.map(|upstream| millis(upstream.latency))),
+      "tls_version" => json!(self.tls_version),
+      "request_id" => json!(header_str(&self.request_id).unwrap_or_default()),
+      "referer" => json!(header_str(&self.referer)),
+      "user_agent" => json!(header_str(&self.user_agent)),
+      _ => Value::Null,
+    }
+  }
+}
+
+const FIELDS: [&str; 15] = [
+  "time",
+  "client_ip",
+  "host",
+  "method",
+  "path",
+  "protocol",
+  "status",
+  "bytes",
+  "duration",
+  "upstream_addr",
+  "upstream_latency",
+  "tls_version",
+  "request_id",
+  "referer",
+  "user_agent",
+];
+
+impl Stream for Counted {
+  type Item = hyper::Result<Bytes>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let this = self.project();
+    let chunk = ready!(this.body.poll_next(cx));
+    if let Some(Ok(chunk)) = &chunk {
+      *this.bytes += chunk.len() as u64;
+    }
+    Poll::Ready(chunk)
+  }
+}
+
+#[pinned_drop]
+impl PinnedDrop for Counted {
+  fn drop(self: Pin<&mut Self>) {
+    let this = self.project();
+    if let Some((log, mut entry)) = this.pending.take() {
+      entry.bytes = Some(*this.bytes);
+      log.write(&entry);
+    }
+  }
+}
+
+fn header_str(value: &Option<HeaderValue>) -> Option<&str> {
+  value.as_ref().and_then(|value| value.to_str().ok())
+}
+
+/// The escaped header value or `-` if the header is missing.
+fn header_field(value: &Option<HeaderValue>) -> String {
+  match value {
+    Some(value) => escape(value.as_bytes()),
+    None => "-".to_string(),
+  }
+}
+
+/// Escapes `"` and `\` with a backslash and all other bytes outside of printable ascii as `\xHH`,
+/// like nginx does, so a client can't end a quoted field or forge log lines.
+fn escape(value: &[u8]) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for &byte in value {
+    match byte {
+      b'"' | b'\\' => {
+        escaped.push('\\');
+        escaped.push(byte as char);
+      }
+      b' '..=b'~' => escaped.push(byte as char),
+      _ => escaped.push_str(&format!("\\x{:02X}", byte)),
+    }
+  }
+  escaped
+}
+
+/// Milliseconds with microsecond precision.
+fn millis(duration: Duration) -> f64 {
+  duration.as_micros() as f64 / 1000.0
+}
+
+fn open(path: &str) -> std::io::Result<File> {
+  OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+  let (year, month, day, hour, minute, second, _) = civil(time);
+  format!(
+    "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+    day,
+    MONTHS[month as usize - 1],
+    year,
+    hour,
+    minute,
+    second
+  )
+}
+
+/// `2000-10-10T13:55:36.123Z`
+fn rfc3339_time(time: SystemTime) -> String {
+  let (year, month, day, hour, minute, second, millis) = civil(time);
+  format!(
+    "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year, month, day, hour, minute, second, millis
+  )
+}
+
+/// Splits a point in time into its utc calendar date and time of day.
+fn civil(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs() as i64;
+  let (days, of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  (
+    year,
+    month,
+    day,
+    of_day / 3600,
+    of_day / 60 % 60,
+    of_day % 60,
+    since_epoch.subsec_millis(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(secs: u64, millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis)
+  }
+
+  fn entry() -> Entry {
+    let req = Request::builder()
+      .uri("/search?q=a")
+      .header(HOST, "example.com")
+      .header(REFERER, "https://example.com/\"quoted\"")
+      .header(
+        USER_AGENT,
+        HeaderValue::from_bytes(b"curl\\8 \xff").unwrap(),
+      )
+      .body(Body::empty())
+      .unwrap();
+    let mut entry = Entry::new(&req, Instant::now());
+    entry.time = at(971186136, 123);
+    entry.status = StatusCode::NOT_FOUND;
+    entry.bytes = Some(42);
+    entry
+  }
+
+  #[test]
+  fn escapes_quotes_and_control_bytes() {
+    assert_eq!(escape(b"Mozilla/5.0"), "Mozilla/5.0");
+    assert_eq!(escape(b"a\"b\\c"), "a\\\"b\\\\c");
+    assert_eq!(escape(b"a\tb\n\x7f\xc3\xa4"), "a\\x09b\\x0A\\x7F\\xC3\\xA4");
+  }
+
+  #[test]
+  fn combined_line() {
+    assert_eq!(
+      entry().common(),
+      "0.0.0.0 - - [10/Oct/2000:13:55:36 +0000] \"GET /search?q=a HTTP/1.1\" 404 42"
+    );
+    assert_eq!(
+      header_field(&entry().referer),
+      "https://example.com/\\\"quoted\\\""
+    );
+    assert_eq!(header_field(&entry().user_agent), "curl\\\\8 \\xFF");
+    assert_eq!(header_field(&None), "-");
+  }
+
+  #[test]
+  fn template_line() {
+    assert_eq!(
+      entry().render("{host} \"{user_agent}\" \"{referer}\" {status} {upstream_addr} {unknown}"),
+      "example.com \"curl\\\\8 \\xFF\" \"https://example.com/\\\"quoted\\\"\" 404 - {unknown}"
+    );
+  }
+
+  #[test]
+  fn formats_times() {
+    assert_eq!(clf_time(at(971186136, 123)), "10/Oct/2000:13:55:36 +0000");
+    assert_eq!(rfc3339_time(at(971186136, 123)), "2000-10-10T13:55:36.123Z");
+    assert_eq!(clf_time(at(0, 0)), "01/Jan/1970:00:00:00 +0000");
+    assert_eq!(rfc3339_time(at(0, 7)), "1970-01-01T00:00:00.007Z");
+  }
+
+  #[test]
+  fn civil_dates() {
+    // leap days, including the century exception of 2100
+    assert_eq!(civil(at(951782400, 0)), (2000, 2, 29, 0, 0, 0, 0));
+    assert_eq!(civil(at(1709251199, 0)), (2024, 2, 29, 23, 59, 59, 0));
+    assert_eq!(civil(at(4107542400, 0)), (2100, 3, 1, 0, 0, 0, 0));
+    assert_eq!(civil(at(1735689599, 999)), (2024, 12, 31, 23, 59, 59, 999));
+    assert_eq!(civil(at(1735689600, 0)), (2025, 1, 1, 0, 0, 0, 0));
+  }
+}
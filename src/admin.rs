@@ -0,0 +1,869 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use mime::APPLICATION_JSON;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::cert::CertStore;
+use crate::config::{Config, LogLevelsConfig};
+use crate::handler::Handler;
+use crate::logging::Logging;
+use crate::maintenance::Maintenance;
+use crate::path::PathPattern;
+use crate::upstream::{MemberState, PoolStats, Upstream};
+
+/// Json api describing the running configuration, upstream members and maintenances can also be
+/// changed. Requires
+/// the bearer token if one is configured, otherwise only accepts connections from loopback
+/// addresses.
+pub(crate) struct Admin {
+  token: Option<String>,
+  config: ConfigInfo,
+  entrypoints: Vec<EntrypointInfo>,
+  services: Vec<ServiceInfo>,
+  upstreams: Vec<(String, Arc<Upstream>)>,
+  cert_store: Arc<CertStore>,
+  /// Serializes writes to the configuration file with applying them.
+  persisting: Mutex<()>,
+  logging: Arc<Logging>,
+}
+
+pub(crate) struct ConfigInfo {
+  pub(crate) path: PathBuf,
+  /// Incremented whenever the running configuration changes.
+  pub(crate) generation: AtomicU64,
+  pub(crate) loaded_at: SystemTime,
+}
+
+#[derive(Deserialize)]
+struct MemberUpdate {
+  state: Option<String>,
+  weight: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MaintenanceUpdate {
+  enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct NewMember {
+  addr: SocketAddr,
+  #[serde(default = "default_weight")]
+  weight: u32,
+}
+
+pub(crate) struct EntrypointInfo {
+  pub(crate) id: String,
+  pub(crate) addr: SocketAddr,
+  pub(crate) tls: bool,
+  pub(crate) redirect_to: Option<String>,
+  pub(crate) middlewares: Vec<String>,
+  pub(crate) handler: Arc<Handler>,
+}
+
+pub(crate) struct ServiceInfo {
+  pub(crate) id: String,
+  pub(crate) kind: &'static str,
+  /// The upstream, redirect location or response status, depending on the kind.
+  pub(crate) target: String,
+}
+
+impl Admin {
+  pub(crate) fn new(
+    token: Option<String>,
+    config: ConfigInfo,
+    entrypoints: Vec<EntrypointInfo>,
+    services: Vec<ServiceInfo>,
+    mut upstreams: Vec<(String, Arc<Upstream>)>,
+    cert_store: Arc<CertStore>,
+    logging: Arc<Logging>,
+  ) -> Self {
+    upstreams.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Self {
+      token,
+      config,
+      entrypoints,
+      services,
+      upstreams,
+      cert_store,
+      persisting: Mutex::new(()),
+      logging,
+    }
+  }
+
+  pub(crate) async fn serve(self: Arc<Self>, addr: SocketAddr) {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+      let admin = self.clone();
+      let peer_addr = conn.remote_addr();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let admin = admin.clone();
+          async move { Ok::<_, Infallible>(admin.handle(peer_addr, req).await) }
+        }))
+      }
+    });
+
+    let result = match Server::try_bind(&addr) {
+      Ok(server) => server.serve(make_service).await,
+      Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+      error!("Admin entrypoint on {} stopped: {}", addr, err);
+    }
+  }
+
+  async fn handle(&self, peer_addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+    if let Err(status) = self.authorize(peer_addr, &req) {
+      return error(status);
+    }
+
+    match (req.method(), req.uri().path()) {
+      (&Method::GET, "/api/config") => self.describe_config(),
+      (&Method::GET, "/api/entrypoints") => self.describe_entrypoints(),
+      (&Method::GET, "/api/routes") => self.describe_routes(),
+      (&Method::GET, "/api/services") => self.describe_services(),
+      (&Method::GET, "/api/upstreams") => self.describe_upstreams().await,
+      (&Method::GET, "/api/certs") => self.describe_certs(),
+      (&Method::GET, "/api/logging") => self.describe_logging(),
+      (&Method::PUT, "/api/logging") => self.change_logging(req).await,
+      (&Method::DELETE, "/api/logging") => {
+        self.logging.reset();
+        self.changed();
+        self.describe_logging()
+      }
+      (&Method::GET, "/api/maintenance") => self.describe_maintenances(),
+      (&Method::PUT, path) if path.starts_with("/api/maintenance/") => {
+        self.change_maintenance(req).await
+      }
+      _ => self.change_member(req).await,
+    }
+  }
+
+  fn describe_logging(&self) -> Response<Body> {
+    respond(json!({
+      "current": self.logging.current(),
+      "configured": self.logging.configured(),
+    }))
+  }
+
+  /// Replaces the log levels until the next change, `DELETE` restores the configured ones.
+  async fn change_logging(&self, req: Request<Body>) -> Response<Body> {
+    let levels: LogLevelsConfig = match hyper::body::to_bytes(req.into_body())
+      .await
+      .ok()
+      .and_then(|body| serde_json::from_slice(&body).ok())
+    {
+      Some(levels) => levels,
+      None => return error(StatusCode::BAD_REQUEST),
+    };
+
+    match self.logging.set(&levels) {
+      Ok(()) => {
+        self.changed();
+        self.describe_logging()
+      }
+      Err(err) => {
+        let mut resp = respond(json!({ "error": err }));
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        resp
+      }
+    }
+  }
+
+  /// The maintenances used by any route.
+  fn maintenances(&self) -> BTreeMap<&str, &Arc<Maintenance>> {
+    self
+      .entrypoints
+      .iter()
+      .flat_map(|entrypoint| entrypoint.handler.routes().entries())
+      .filter_map(|(_, _, _, route)| route.maintenance.as_ref())
+      .map(|maintenance| (maintenance.id(), maintenance))
+      .collect()
+  }
+
+  fn describe_maintenances(&self) -> Response<Body> {
+    respond(Value::Array(
+      self
+        .maintenances()
+        .values()
+        .map(|maintenance| describe_maintenance(maintenance))
+        .collect(),
+    ))
+  }
+
+  /// `PUT /api/maintenance/{id}` with `{"enabled": true}` enables a maintenance until it is
+  /// disabled again or its flag file changes.
+  async fn change_maintenance(&self, req: Request<Body>) -> Response<Body> {
+    let id = match req
+      .uri()
+      .path()
+      .strip_prefix("/api/maintenance/")
+      .and_then(percent_decode)
+    {
+      Some(id) => id,
+      None => return error(StatusCode::BAD_REQUEST),
+    };
+    let maintenance = match self.maintenances().get(id.as_str()) {
+      Some(maintenance) => Arc::clone(maintenance),
+      None => return error(StatusCode::NOT_FOUND),
+    };
+
+    let update: MaintenanceUpdate = match hyper::body::to_bytes(req.into_body())
+      .await
+      .ok()
+      .and_then(|body| serde_json::from_slice(&body).ok())
+    {
+      Some(update) => update,
+      None => return error(StatusCode::BAD_REQUEST),
+    };
+
+    maintenance.set_enabled(update.enabled);
+    self.changed();
+
+    respond(describe_maintenance(&maintenance))
+  }
+
+  /// `POST /api/upstreams/{id}/members` adds a temporary member, `PATCH` and `DELETE` on
+  /// `/api/upstreams/{id}/members/{addr}` change or remove one. With `?persist=true` a `PATCH` of a
+  /// configured member is written to the configuration file before it is applied.
+  async fn change_member(&self, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    let segments: Vec<_> = match path.strip_prefix("/api/upstreams/") {
+      Some(rest) => rest.split('/').collect(),
+      None => return error(StatusCode::NOT_FOUND),
+    };
+    let (id, addr) = match segments.as_slice() {
+      [id, "members"] => (*id, None),
+      [id, "members", addr] => match percent_decode(addr).and_then(|addr| addr.parse().ok()) {
+        Some(addr) => (*id, Some(addr)),
+        None => return error(StatusCode::BAD_REQUEST),
+      },
+      _ => return error(StatusCode::NOT_FOUND),
+    };
+    let upstream = match self
+      .upstreams
+      .iter()
+      .find(|(upstream_id, _)| upstream_id == id)
+    {
+      Some((_, upstream)) => upstream,
+      None => return error(StatusCode::NOT_FOUND),
+    };
+    let persist = req
+      .uri()
+      .query()
+      .is_some_and(|query| query.split('&').any(|pair| pair == "persist=true"));
+
+    let method = req.method().clone();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+      Ok(body) => body,
+      Err(_) => return error(StatusCode::BAD_REQUEST),
+    };
+
+    let addr = match (method, addr) {
+      // temporary members are never written to the configuration
+      (Method::POST | Method::DELETE, _) if persist => return error(StatusCode::BAD_REQUEST),
+      (Method::POST, None) => {
+        let new: NewMember = match serde_json::from_slice(&body) {
+          Ok(new) => new,
+          Err(_) => return error(StatusCode::BAD_REQUEST),
+        };
+        if !upstream.add_member(new.addr, new.weight).await {
+          return error(StatusCode::CONFLICT);
+        }
+        new.addr
+      }
+      (Method::PATCH, Some(addr)) => {
+        let update: MemberUpdate = match serde_json::from_slice(&body) {
+          Ok(update) => update,
+          Err(_) => return error(StatusCode::BAD_REQUEST),
+        };
+        let state = match update.state.as_deref().map(MemberState::parse) {
+          Some(None) => return error(StatusCode::BAD_REQUEST),
+          Some(state) => state,
+          None => None,
+        };
+
+        if persist {
+          // draining is a transition, the configuration only knows active and disabled members
+          if state == Some(MemberState::Draining) {
+            return error(StatusCode::BAD_REQUEST);
+          }
+          let stats = upstream.pool_stats().await;
+          match stats.iter().find(|stats| stats.addr == addr) {
+            None => return error(StatusCode::NOT_FOUND),
+            Some(stats) if stats.temporary => return error(StatusCode::CONFLICT),
+            Some(_) => {}
+          }
+
+          // held until the change is applied, so the file and the running state change in order
+          let _guard = self.persisting.lock().await;
+          if let Err(err) = self.persist(id, addr, state, update.weight) {
+            error!(
+              "Unable to persist member {} of upstream {}: {}",
+              addr, id, err
+            );
+            return error(StatusCode::INTERNAL_SERVER_ERROR);
+          }
+          if !upstream.update_member(addr, state, update.weight).await {
+            return error(StatusCode::NOT_FOUND);
+          }
+        } else if !upstream.update_member(addr, state, update.weight).await {
+          return error(StatusCode::NOT_FOUND);
+        }
+        addr
+      }
+      (Method::DELETE, Some(addr)) => {
+        let stats = upstream.pool_stats().await;
+        match stats.iter().find(|stats| stats.addr == addr) {
+          None => return error(StatusCode::NOT_FOUND),
+          // configured members can only be disabled
+          Some(stats) if !stats.temporary => return error(StatusCode::CONFLICT),
+          Some(_) => {}
+        }
+        upstream.remove_member(addr).await;
+        addr
+      }
+      _ => return error(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    self.changed();
+    info!("Changed member {} of upstream {}", addr, id);
+
+    match upstream
+      .pool_stats()
+      .await
+      .iter()
+      .find(|stats| stats.addr == addr)
+    {
+      Some(stats) => respond(describe_member(stats)),
+      None => {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        resp
+      }
+    }
+  }
+
+  /// Writes the state and weight of a configured member to the configuration file. Only the
+  /// `weights` and `disabled` keys of the upstream are rewritten, everything else is kept as is.
+  fn persist(
+    &self,
+    id: &str,
+    addr: SocketAddr,
+    state: Option<MemberState>,
+    weight: Option<u32>,
+  ) -> io::Result<()> {
+    let path = &self.config.path;
+    let source = fs::read_to_string(path)?;
+    let edited = edit_member(&source, id, addr, state, weight).map_err(io::Error::other)?;
+    write_atomic(path, &edited)?;
+    info!(
+      "Persisted member {} of upstream {} to {}",
+      addr,
+      id,
+      path.display()
+    );
+    Ok(())
+  }
+
+  fn changed(&self) {
+    self.config.generation.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn authorize(&self, peer_addr: SocketAddr, req: &Request<Body>) -> Result<(), StatusCode> {
+    let token = match &self.token {
+      Some(token) => token,
+      None if peer_addr.ip().to_canonical().is_loopback() => return Ok(()),
+      None => return Err(StatusCode::FORBIDDEN),
+    };
+
+    let supplied = req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|raw| raw.to_str().ok())
+      .and_then(|raw| raw.strip_prefix("Bearer "));
+
+    match supplied {
+      Some(supplied) if constant_time_eq(supplied.as_bytes(), token.as_bytes()) => Ok(()),
+      _ => Err(StatusCode::UNAUTHORIZED),
+    }
+  }
+
+  fn describe_config(&self) -> Response<Body> {
+    respond(json!({
+      "path": self.config.path.display().to_string(),
+      "generation": self.config.generation.load(Ordering::Relaxed),
+      "loaded_at": unix(self.config.loaded_at),
+    }))
+  }
+
+  fn describe_entrypoints(&self) -> Response<Body> {
+    respond(Value::Array(
+      self
+        .entrypoints
+        .iter()
+        .map(|entrypoint| {
+          json!({
+            "id": entrypoint.id,
+            "addr": entrypoint.addr.to_string(),
+            "tls": entrypoint.tls,
+            "redirect_to": entrypoint.redirect_to,
+            "middlewares": entrypoint.middlewares,
+          })
+        })
+        .collect(),
+    ))
+  }
+
+  /// The compiled routes of every entrypoint, by host and descending priority.
+  fn describe_routes(&self) -> Response<Body> {
+    let mut routes = serde_json::Map::new();
+    for entrypoint in &self.entrypoints {
+      let entries = entrypoint
+        .handler
+        .routes()
+        .entries()
+        .into_iter()
+        .map(|(host, priority, path, route)| {
+          json!({
+            "name": route.name,
+            "host": host,
+            "priority": priority,
+            "path": path.to_string(),
+            "exact": matches!(path, PathPattern::Segments(_, true)),
+            "service": route.service_id,
+            "middlewares": route.middleware_ids,
+            "matcher": route.matcher.is_some(),
+            "rewrite": route.rewrite.is_some(),
+            "maintenance": route
+              .maintenance
+              .as_ref()
+              .map(|maintenance| maintenance.is_enabled()),
+            "intercept_errors": route.intercept_errors,
+          })
+        })
+        .collect();
+      routes.insert(entrypoint.id.clone(), Value::Array(entries));
+    }
+
+    respond(Value::Object(routes))
+  }
+
+  fn describe_services(&self) -> Response<Body> {
+    respond(Value::Array(
+      self
+        .services
+        .iter()
+        .map(|service| {
+          json!({
+            "id": service.id,
+            "kind": service.kind,
+            "target": service.target,
+          })
+        })
+        .collect(),
+    ))
+  }
+
+  async fn describe_upstreams(&self) -> Response<Body> {
+    let mut upstreams = Vec::with_capacity(self.upstreams.len());
+    for (id, upstream) in &self.upstreams {
+      let mut members = upstream.pool_stats().await;
+      members.sort_by_key(|stats| stats.addr);
+
+      upstreams.push(json!({
+        "id": id,
+        "members": members.iter().map(describe_member).collect::<Vec<_>>(),
+      }));
+    }
+
+    respond(Value::Array(upstreams))
+  }
+
+  fn describe_certs(&self) -> Response<Body> {
+    let mut certs = self.cert_store.certificates();
+    certs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    respond(Value::Array(
+      certs
+        .iter()
+        .map(|cert| {
+          json!({
+            "name": cert.name,
+            "issuer": cert.issuer,
+            "not_before": cert.not_before,
+            "not_after": cert.not_after,
+          })
+        })
+        .collect(),
+    ))
+  }
+}
+
+fn describe_member(stats: &PoolStats) -> Value {
+  json!({
+    "addr": stats.addr.to_string(),
+    "state": stats.state.as_str(),
+    "weight": stats.weight,
+    "temporary": stats.temporary,
+    "drained": stats.state != MemberState::Active && stats.active == 0,
+    "healthy": stats.failures == 0,
+    "failures": stats.failures,
+    "last_error": stats.last_error,
+    "idle": stats.idle,
+    "active": stats.active,
+  })
+}
+
+fn describe_maintenance(maintenance: &Maintenance) -> Value {
+  json!({
+    "id": maintenance.id(),
+    "enabled": maintenance.is_enabled(),
+  })
+}
+
+fn respond(value: Value) -> Response<Body> {
+  let mut resp = Response::new(Body::from(value.to_string()));
+  resp
+    .headers_mut()
+    .insert(CONTENT_TYPE, APPLICATION_JSON.as_ref().parse().unwrap());
+  resp
+}
+
+fn error(status: StatusCode) -> Response<Body> {
+  let mut resp = respond(json!({
+    "error": status.canonical_reason().unwrap_or(""),
+  }));
+  *resp.status_mut() = status;
+  resp
+}
+
+fn unix(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+/// Compares without returning early, so the token can not be guessed from response timings.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Decodes `%5B::1%5D:80` so ipv6 addresses can be used in paths.
+fn percent_decode(raw: &str) -> Option<String> {
+  let mut decoded = Vec::with_capacity(raw.len());
+  let mut bytes = raw.bytes();
+  while let Some(byte) = bytes.next() {
+    if byte == b'%' {
+      let hex = [bytes.next()?, bytes.next()?];
+      decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    } else {
+      decoded.push(byte);
+    }
+  }
+  String::from_utf8(decoded).ok()
+}
+
+/// Writes next to the file and renames, so a crash can not leave a truncated configuration.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  fs::write(&tmp, contents)?;
+  fs::rename(&tmp, path)
+}
+
+/// Changes one member in `weights` and `disabled` of the upstream, the other lines are kept.
+fn edit_member(
+  source: &str,
+  id: &str,
+  addr: SocketAddr,
+  state: Option<MemberState>,
+  weight: Option<u32>,
+) -> Result<String, String> {
+  let (mut weights, mut disabled) = configured_members(source, id)?;
+  if let Some(weight) = weight {
+    match weight {
+      1 => weights.remove(&addr),
+      weight => weights.insert(addr, weight),
+    };
+  }
+  match state {
+    Some(MemberState::Disabled) => {
+      disabled.insert(addr);
+    }
+    Some(_) => {
+      disabled.remove(&addr);
+    }
+    None => {}
+  }
+
+  let quote = |addr: &SocketAddr| format!("'{}'", addr);
+  let weights_value = (!weights.is_empty()).then(|| {
+    let entries: Vec<_> = weights
+      .iter()
+      .map(|(addr, weight)| format!("{}: {}", quote(addr), weight))
+      .collect();
+    format!("{{ {} }}", entries.join(", "))
+  });
+  let disabled_value = (!disabled.is_empty()).then(|| {
+    let entries: Vec<_> = disabled.iter().map(quote).collect();
+    format!("[ {} ]", entries.join(", "))
+  });
+
+  let mut lines: Vec<String> = source.lines().map(str::to_string).collect();
+  for (key, value) in [("weights", weights_value), ("disabled", disabled_value)] {
+    let item = upstream_item(&lines, id)?;
+    match (find_key(&lines, &item, key), value) {
+      (Some(range), Some(value)) => {
+        let line = &lines[range.start];
+        let prefix = line[..line.find(key).unwrap_or(0)].to_string();
+        lines.splice(range, [format!("{}{}: {}", prefix, key, value)]);
+      }
+      (Some(range), None) => {
+        lines.drain(range);
+      }
+      (None, Some(value)) => {
+        // after the other member keys
+        let previous = find_key(&lines, &item, "weights")
+          .or_else(|| find_key(&lines, &item, "addrs"))
+          .ok_or_else(|| format!("upstream {} has no addrs", id))?;
+        let line = format!("{}{}: {}", " ".repeat(item.indent), key, value);
+        lines.insert(previous.end, line);
+      }
+      (None, None) => {}
+    }
+  }
+
+  let mut edited = lines.join("\n");
+  if source.ends_with('\n') {
+    edited.push('\n');
+  }
+
+  // the edit is only written if it reads back as intended
+  if configured_members(&edited, id)? != (weights, disabled) {
+    return Err(format!("unable to edit upstream {}", id));
+  }
+  Ok(edited)
+}
+
+type Members = (BTreeMap<SocketAddr, u32>, BTreeSet<SocketAddr>);
+
+fn configured_members(source: &str, id: &str) -> Result<Members, String> {
+  let config: Config = serde_yaml::from_str(source).map_err(|err| err.to_string())?;
+  let upstream = config
+    .upstreams
+    .into_iter()
+    .find(|upstream| upstream.id == id)
+    .ok_or_else(|| format!("upstream {} is not configured", id))?;
+  Ok((
+    upstream.weights.into_iter().collect(),
+    upstream.disabled.into_iter().collect(),
+  ))
+}
+
+/// The lines of an entry of the block sequence `upstreams`.
+struct Item {
+  lines: Range<usize>,
+  /// Indentation of the keys of the entry.
+  indent: usize,
+}
+
+fn indentation(line: &str) -> usize {
+  line.len() - line.trim_start().len()
+}
+
+fn is_content(line: &str) -> bool {
+  let trimmed = line.trim_start();
+  !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+fn upstream_item(lines: &[String], id: &str) -> Result<Item, String> {
+  let start = lines
+    .iter()
+    .position(|line| line.trim_end() == "upstreams:")
+    .ok_or("upstreams has to be a block sequence to be edited")?
+    + 1;
+  let end = (start..lines.len())
+    .find(|&i| is_content(&lines[i]) && indentation(&lines[i]) == 0)
+    .unwrap_or(lines.len());
+
+  let dashes: Vec<usize> = (start..end)
+    .filter(|&i| lines[i].trim_start().starts_with("- "))
+    .collect();
+  let dash_indent = dashes
+    .first()
+    .map(|&i| indentation(&lines[i]))
+    .ok_or("upstreams is empty")?;
+  let items: Vec<usize> = dashes
+    .into_iter()
+    .filter(|&i| indentation(&lines[i]) == dash_indent)
+    .collect();
+
+  for (n, &first) in items.iter().enumerate() {
+    let item = Item {
+      lines: first..items.get(n + 1).copied().unwrap_or(end),
+      indent: dash_indent + 2,
+    };
+    let matches = find_key(lines, &item, "id").is_some_and(|range| {
+      let line = &lines[range.start];
+      let value = line[line.find("id:").unwrap_or(0) + 3..].trim();
+      value.trim_matches(|c| c == '\'' || c == '"') == id
+    });
+    if matches {
+      return Ok(item);
+    }
+  }
+
+  Err(format!("upstream {} is not a block mapping", id))
+}
+
+/// The lines of a key of the entry including its nested lines.
+fn find_key(lines: &[String], item: &Item, key: &str) -> Option<Range<usize>> {
+  let prefix = format!("{}:", key);
+  let start = item.lines.clone().find(|&i| {
+    let line = &lines[i];
+    let content = match line.trim_start().strip_prefix("- ") {
+      Some(content) if i == item.lines.start => content,
+      _ if indentation(line) == item.indent => line.trim_start(),
+      _ => return false,
+    };
+    content.starts_with(&prefix)
+  })?;
+
+  // nested lines, comments and blank lines after them belong to the next key
+  let nested = lines[start + 1..item.lines.end]
+    .iter()
+    .take_while(|line| !is_content(line) || indentation(line) > item.indent)
+    .enumerate()
+    .filter(|(_, line)| is_content(line))
+    .last()
+    .map_or(0, |(i, _)| i + 1);
+  Some(start..start + 1 + nested)
+}
+
+fn default_weight() -> u32 {
+  1
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CONFIG: &str = "\
+# upstreams are edited by the admin api
+services: {}
+upstreams:
+  # the api servers
+  - id: api
+    addrs: [ 127.0.0.1:8000, 127.0.0.1:8001, '[::1]:8002' ] # keep sorted
+    weights:
+      127.0.0.1:8000: 3 # the big one
+    sni: api.local
+  - id: other
+    addrs:
+      - 127.0.0.1:9000
+    disabled: [ 127.0.0.1:9000 ]
+metrics:
+  addr: 127.0.0.1:9100
+";
+
+  fn addr(raw: &str) -> SocketAddr {
+    raw.parse().unwrap()
+  }
+
+  #[test]
+  fn keeps_comments_and_other_keys() {
+    let edited = edit_member(
+      CONFIG,
+      "api",
+      addr("127.0.0.1:8001"),
+      Some(MemberState::Disabled),
+      Some(2),
+    )
+    .unwrap();
+
+    assert_eq!(
+      edited,
+      CONFIG
+        .replace(
+          "    weights:\n      127.0.0.1:8000: 3 # the big one\n",
+          "    weights: { '127.0.0.1:8000': 3, '127.0.0.1:8001': 2 }\n"
+        )
+        .replace(
+          "    sni: api.local\n",
+          "    disabled: [ '127.0.0.1:8001' ]\n    sni: api.local\n"
+        )
+    );
+  }
+
+  #[test]
+  fn removes_default_values() {
+    let edited = edit_member(
+      CONFIG,
+      "api",
+      addr("127.0.0.1:8000"),
+      Some(MemberState::Active),
+      Some(1),
+    )
+    .unwrap();
+    assert_eq!(
+      edited,
+      CONFIG.replace("    weights:\n      127.0.0.1:8000: 3 # the big one\n", "")
+    );
+
+    let edited = edit_member(
+      CONFIG,
+      "other",
+      addr("127.0.0.1:9000"),
+      Some(MemberState::Active),
+      None,
+    )
+    .unwrap();
+    assert_eq!(
+      edited,
+      CONFIG.replace("    disabled: [ 127.0.0.1:9000 ]\n", "")
+    );
+  }
+
+  #[test]
+  fn quotes_ipv6_members() {
+    let edited = edit_member(
+      CONFIG,
+      "api",
+      addr("[::1]:8002"),
+      Some(MemberState::Disabled),
+      None,
+    )
+    .unwrap();
+    let (_, disabled) = configured_members(&edited, "api").unwrap();
+    assert!(disabled.contains(&addr("[::1]:8002")));
+    // the other upstream is untouched
+    assert!(edited.contains("    disabled: [ 127.0.0.1:9000 ]\n"));
+  }
+
+  #[test]
+  fn rejects_unknown_upstreams() {
+    assert!(edit_member(CONFIG, "missing", addr("127.0.0.1:8000"), None, Some(2)).is_err());
+  }
+
+  #[test]
+  fn rejects_flow_sequences() {
+    let config = "services: {}\nupstreams: [ { id: api, addrs: [ 127.0.0.1:8000 ] } ]\n";
+    assert!(edit_member(config, "api", addr("127.0.0.1:8000"), None, Some(2)).is_err());
+  }
+}
@@ -10,12 +10,21 @@ use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
 use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey, SigningKey};
 use tokio_rustls::rustls::PrivateKey;
 use tokio_rustls::webpki::DnsName;
+use x509_parser::parse_x509_certificate;
 
 pub(crate) struct CertStore {
   certs: HashMap<String, Arc<CertifiedKey>>,
   fallback_name: String,
 }
 
+/// Timestamps are unix seconds.
+pub(crate) struct CertInfo {
+  pub(crate) name: String,
+  pub(crate) issuer: String,
+  pub(crate) not_before: i64,
+  pub(crate) not_after: i64,
+}
+
 impl CertStore {
   pub(crate) fn new(fallback_name: DnsName) -> Self {
     let mut name = <DnsName as AsRef<str>>::as_ref(&fallback_name).to_string();
@@ -37,6 +46,23 @@ impl CertStore {
 
     self.certs.insert(name, cert)
   }
+
+  /// Validity and issuer of the leaf certificate per name.
+  pub(crate) fn certificates(&self) -> Vec<CertInfo> {
+    self
+      .certs
+      .iter()
+      .filter_map(|(name, key)| {
+        let (_, cert) = parse_x509_certificate(&key.cert.first()?.0).ok()?;
+        Some(CertInfo {
+          name: name.clone(),
+          issuer: cert.issuer().to_string(),
+          not_before: cert.validity().not_before.timestamp(),
+          not_after: cert.validity().not_after.timestamp(),
+        })
+      })
+      .collect()
+  }
 }
 
 impl ResolvesServerCert for CertStore {
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use serde::Deserialize;
@@ -13,6 +14,87 @@ pub(crate) struct Config {
   pub(crate) upstreams: Vec<UpstreamConfig>,
   #[serde(default)]
   pub(crate) certs: Vec<CertificateConfig>,
+  #[serde(default)]
+  pub(crate) maintenance: Vec<MaintenanceConfig>,
+  #[serde(default)]
+  pub(crate) error_pages: Vec<ErrorPagesConfig>,
+  #[serde(default)]
+  pub(crate) middlewares: MiddlewareConfig,
+  pub(crate) metrics: Option<MetricsConfig>,
+  pub(crate) opentelemetry: Option<OpenTelemetryConfig>,
+  pub(crate) admin: Option<AdminConfig>,
+  #[serde(default)]
+  pub(crate) logging: LoggingConfig,
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct LoggingConfig {
+  #[serde(default)]
+  pub(crate) format: LogFormatConfig,
+  #[serde(default)]
+  pub(crate) timestamps: LogTimestampsConfig,
+  #[serde(flatten)]
+  pub(crate) levels: LogLevelsConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormatConfig {
+  #[default]
+  Text,
+  Json,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogTimestampsConfig {
+  #[default]
+  Rfc3339,
+  /// Seconds since the epoch with millisecond precision.
+  Unix,
+  /// Seconds since pux started.
+  Uptime,
+  None,
+}
+
+/// Also accepted by the admin api to change levels at runtime.
+#[derive(Deserialize, Default)]
+pub(crate) struct LogLevelsConfig {
+  /// For targets without their own level, `info` if not set.
+  pub(crate) level: Option<String>,
+  /// By target prefix, e.g. `pux::upstream: debug`.
+  #[serde(default)]
+  pub(crate) targets: HashMap<String, String>,
+}
+
+/// Serves prometheus metrics on its own address.
+#[derive(Deserialize)]
+pub(crate) struct MetricsConfig {
+  pub(crate) addr: SocketAddr,
+  #[serde(default = "default_metrics_path")]
+  pub(crate) path: String,
+}
+
+/// Json api to inspect the running configuration.
+#[derive(Deserialize)]
+pub(crate) struct AdminConfig {
+  pub(crate) addr: SocketAddr,
+  /// Required as bearer token if set, otherwise only loopback clients are accepted.
+  pub(crate) token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OpenTelemetryConfig {
+  /// Base url of the otlp/http collector, `/v1/traces` is appended.
+  pub(crate) endpoint: String,
+  #[serde(default = "default_service_name")]
+  pub(crate) service_name: String,
+  /// Fraction of new traces that are recorded.
+  #[serde(default = "default_sample_ratio")]
+  pub(crate) sample_ratio: f64,
+  /// Follow the sampling decision of an incoming `traceparent`.
+  #[serde(default = "default_true")]
+  pub(crate) parent_based: bool,
 }
 
 #[derive(Deserialize)]
@@ -20,20 +102,125 @@ pub(crate) struct EntrypointConfig {
   pub(crate) id: String,
   pub(crate) addr: SocketAddr,
   pub(crate) tls: bool,
+  pub(crate) redirect_to: Option<String>,
+  pub(crate) error_pages: Option<String>,
+  /// Run before the middlewares of every route on this entrypoint.
+  #[serde(default)]
+  pub(crate) middlewares: Vec<String>,
+  /// Networks whose `X-Request-Id` is kept instead of generating a new one.
+  #[serde(default)]
+  pub(crate) trusted_request_ids: Vec<String>,
+  pub(crate) access_log: Option<AccessLogConfig>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AccessLogConfig {
+  #[serde(default)]
+  pub(crate) format: AccessLogFormatConfig,
+  /// Required for the `template` format.
+  pub(crate) template: Option<String>,
+  /// Written to stdout if not set.
+  pub(crate) path: Option<String>,
+  /// Fraction of `2xx` responses to log, everything else is always logged.
+  #[serde(default = "default_access_log_sample")]
+  pub(crate) sample_success: f64,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccessLogFormatConfig {
+  #[default]
+  Common,
+  Combined,
+  Json,
+  Template,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct RouteConfig {
+  /// Defaults to the host followed by the path.
+  pub(crate) id: Option<String>,
   pub(crate) host: String,
   #[serde(default)]
-  pub(crate) path: Vec<String>,
+  pub(crate) path: PathConfig,
   pub(crate) entrypoints: Vec<String>,
   pub(crate) service: String,
+  pub(crate) strip_prefix: Option<String>,
+  pub(crate) add_prefix: Option<String>,
+  pub(crate) rewrite: Option<RewriteConfig>,
+  pub(crate) maintenance: Option<String>,
+  pub(crate) error_pages: Option<String>,
+  #[serde(default)]
+  pub(crate) intercept_errors: bool,
+  #[serde(rename = "match")]
+  pub(crate) matcher: Option<MatchConfig>,
+  #[serde(default)]
+  pub(crate) priority: i32,
+  #[serde(default)]
+  pub(crate) middlewares: Vec<String>,
+}
+
+/// All configured conditions have to match, `any` matches if one of its entries does.
+#[derive(Deserialize)]
+pub(crate) struct MatchConfig {
+  #[serde(default)]
+  pub(crate) method: Vec<String>,
+  #[serde(default)]
+  pub(crate) headers: Vec<ValueMatchConfig>,
+  #[serde(default)]
+  pub(crate) query: Vec<ValueMatchConfig>,
+  #[serde(default)]
+  pub(crate) client_ip: Vec<String>,
+  #[serde(default)]
+  pub(crate) sni: Vec<String>,
+  #[serde(default)]
+  pub(crate) all: Vec<MatchConfig>,
+  #[serde(default)]
+  pub(crate) any: Vec<MatchConfig>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ValueMatchConfig {
+  pub(crate) name: String,
+  pub(crate) value: Option<String>,
+  pub(crate) regex: Option<String>,
+}
+
+/// A plain string is a prefix pattern, see [`crate::path::PathPattern::parse`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum PathConfig {
+  Prefix(String),
+  /// The former list of prefix segments, `["", "api"]` is the same as `/api`.
+  Segments(Vec<String>),
+  Exact {
+    exact: String,
+  },
+  Regex {
+    regex: String,
+  },
+}
+
+impl Default for PathConfig {
+  fn default() -> Self {
+    Self::Prefix("/".to_string())
+  }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RewriteConfig {
+  pub(crate) regex: String,
+  pub(crate) replacement: String,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct ServiceConfig {
+  #[serde(default)]
   pub(crate) proxy: Vec<ProxyServiceConfig>,
+  #[serde(default)]
+  pub(crate) redirect: Vec<RedirectServiceConfig>,
+  #[serde(default)]
+  pub(crate) respond: Vec<RespondServiceConfig>,
 }
 
 #[derive(Deserialize)]
@@ -42,11 +229,316 @@ pub(crate) struct ProxyServiceConfig {
   pub(crate) upstream: String,
 }
 
+#[derive(Deserialize)]
+pub(crate) struct RedirectServiceConfig {
+  pub(crate) id: String,
+  pub(crate) location: String,
+  #[serde(default = "default_redirect_code")]
+  pub(crate) code: u16,
+  #[serde(default = "default_true")]
+  pub(crate) preserve_path: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RespondServiceConfig {
+  pub(crate) id: String,
+  #[serde(default = "default_respond_status")]
+  pub(crate) status: u16,
+  #[serde(default)]
+  pub(crate) headers: HashMap<String, String>,
+  pub(crate) body: Option<String>,
+  pub(crate) body_file: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct MiddlewareConfig {
+  #[serde(default)]
+  pub(crate) basic_auth: Vec<BasicAuthConfig>,
+  #[serde(default)]
+  pub(crate) forward_auth: Vec<ForwardAuthConfig>,
+  #[serde(default)]
+  pub(crate) jwt: Vec<JwtConfig>,
+  #[serde(default)]
+  pub(crate) rate_limit: Vec<RateLimitConfig>,
+  #[serde(default)]
+  pub(crate) concurrency_limit: Vec<ConcurrencyLimitConfig>,
+  #[serde(default)]
+  pub(crate) ip_filter: Vec<IpFilterConfig>,
+  #[serde(default)]
+  pub(crate) compression: Vec<CompressionConfig>,
+  #[serde(default)]
+  pub(crate) headers: Vec<HeadersConfig>,
+  #[serde(default)]
+  pub(crate) security_headers: Vec<SecurityHeadersConfig>,
+  #[serde(default)]
+  pub(crate) cors: Vec<CorsConfig>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BasicAuthConfig {
+  pub(crate) id: String,
+  #[serde(default = "default_realm")]
+  pub(crate) realm: String,
+  /// Entries in the htpasswd `user:hash` format.
+  #[serde(default)]
+  pub(crate) users: Vec<String>,
+  pub(crate) htpasswd: Option<String>,
+  #[serde(default)]
+  pub(crate) strip_header: bool,
+  pub(crate) user_header: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ForwardAuthConfig {
+  pub(crate) id: String,
+  pub(crate) upstream: String,
+  #[serde(default = "default_forward_auth_path")]
+  pub(crate) path: String,
+  #[serde(default = "default_forward_auth_request_headers")]
+  pub(crate) request_headers: Vec<String>,
+  #[serde(default)]
+  pub(crate) response_headers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct JwtConfig {
+  pub(crate) id: String,
+  #[serde(default = "default_realm")]
+  pub(crate) realm: String,
+  /// Shared secret for HS256 signed tokens.
+  pub(crate) secret: Option<String>,
+  pub(crate) jwks_file: Option<String>,
+  /// Upstream serving the key set at `jwks_uri`, fetched every `jwks_refresh` seconds.
+  pub(crate) jwks_upstream: Option<String>,
+  pub(crate) jwks_uri: Option<String>,
+  #[serde(default = "default_jwks_refresh")]
+  pub(crate) jwks_refresh: u64,
+  #[serde(default = "default_jwt_algorithms")]
+  pub(crate) algorithms: Vec<String>,
+  pub(crate) issuer: Option<String>,
+  pub(crate) audience: Option<String>,
+  #[serde(default = "default_jwt_leeway")]
+  pub(crate) leeway: u64,
+  #[serde(default)]
+  pub(crate) required_claims: Vec<String>,
+  #[serde(default)]
+  pub(crate) claim_headers: Vec<ClaimHeaderConfig>,
+  #[serde(default)]
+  pub(crate) strip_header: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ClaimHeaderConfig {
+  pub(crate) claim: String,
+  pub(crate) header: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RateLimitConfig {
+  pub(crate) id: String,
+  /// Requests allowed per `period` seconds.
+  pub(crate) rate: f64,
+  #[serde(default = "default_rate_limit_period")]
+  pub(crate) period: f64,
+  /// Defaults to `rate`.
+  pub(crate) burst: Option<f64>,
+  #[serde(default)]
+  pub(crate) key: RateLimitKeyConfig,
+  /// Required for the `header` key.
+  pub(crate) header: Option<String>,
+  #[serde(default = "default_ipv4_prefix")]
+  pub(crate) ipv4_prefix: u8,
+  #[serde(default = "default_ipv6_prefix")]
+  pub(crate) ipv6_prefix: u8,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RateLimitKeyConfig {
+  #[default]
+  Ip,
+  Header,
+  User,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConcurrencyLimitConfig {
+  pub(crate) id: String,
+  #[serde(flatten)]
+  pub(crate) concurrency: ConcurrencyConfig,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConcurrencyConfig {
+  /// Maximum in-flight requests, the initial limit if adaptive.
+  pub(crate) limit: usize,
+  #[serde(default)]
+  pub(crate) queue: usize,
+  /// Milliseconds a request may wait in the queue.
+  #[serde(default = "default_queue_timeout")]
+  pub(crate) queue_timeout: u64,
+  pub(crate) adaptive: Option<AdaptiveConfig>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdaptiveConfig {
+  #[serde(default = "default_min_limit")]
+  pub(crate) min_limit: usize,
+  pub(crate) max_limit: usize,
+  /// Milliseconds, slower responses lower the limit.
+  pub(crate) latency: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IpFilterConfig {
+  pub(crate) id: String,
+  #[serde(default)]
+  pub(crate) allow: Vec<String>,
+  #[serde(default)]
+  pub(crate) deny: Vec<String>,
+  /// Files with one address or network per line, reloaded on change.
+  #[serde(default)]
+  pub(crate) allow_files: Vec<String>,
+  #[serde(default)]
+  pub(crate) deny_files: Vec<String>,
+  /// Peers whose `X-Forwarded-For` header is used to determine the client address.
+  #[serde(default)]
+  pub(crate) trusted_proxies: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CompressionConfig {
+  pub(crate) id: String,
+  /// Ordered by preference.
+  #[serde(default = "default_encodings")]
+  pub(crate) encodings: Vec<EncodingConfig>,
+  /// Responses with a smaller content length are passed through.
+  #[serde(default = "default_compression_min_size")]
+  pub(crate) min_size: u64,
+  /// Exact types or prefixes ending with `/`, `text/event-stream` has to be listed exactly.
+  #[serde(default = "default_compression_mime_types")]
+  pub(crate) mime_types: Vec<String>,
+  pub(crate) level: Option<i32>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EncodingConfig {
+  Zstd,
+  #[serde(rename = "br")]
+  Brotli,
+  Gzip,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HeadersConfig {
+  pub(crate) id: String,
+  #[serde(default)]
+  pub(crate) request: HeaderRulesConfig,
+  #[serde(default)]
+  pub(crate) response: HeaderRulesConfig,
+}
+
+#[derive(Deserialize, Default)]
+pub(crate) struct HeaderRulesConfig {
+  #[serde(default)]
+  pub(crate) set: HashMap<String, String>,
+  #[serde(default)]
+  pub(crate) append: HashMap<String, String>,
+  #[serde(default)]
+  pub(crate) remove: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SecurityHeadersConfig {
+  pub(crate) id: String,
+  #[serde(default = "default_hsts")]
+  pub(crate) hsts: Option<HstsConfig>,
+  #[serde(default = "default_true")]
+  pub(crate) content_type_options: bool,
+  #[serde(default = "default_frame_options")]
+  pub(crate) frame_options: Option<String>,
+  #[serde(default = "default_referrer_policy")]
+  pub(crate) referrer_policy: Option<String>,
+  pub(crate) permissions_policy: Option<String>,
+  pub(crate) content_security_policy: Option<String>,
+  /// Replace values set by the backend.
+  #[serde(default, rename = "override")]
+  pub(crate) override_existing: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HstsConfig {
+  #[serde(default = "default_hsts_max_age")]
+  pub(crate) max_age: u64,
+  #[serde(default)]
+  pub(crate) include_subdomains: bool,
+  #[serde(default)]
+  pub(crate) preload: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CorsConfig {
+  pub(crate) id: String,
+  /// Exact origins, `*` or wildcard subdomains like `https://*.example.com`.
+  #[serde(default)]
+  pub(crate) origins: Vec<String>,
+  #[serde(default)]
+  pub(crate) origin_regex: Vec<String>,
+  #[serde(default = "default_cors_methods")]
+  pub(crate) methods: Vec<String>,
+  /// Allowed request headers, all requested headers are allowed if empty.
+  #[serde(default)]
+  pub(crate) headers: Vec<String>,
+  #[serde(default)]
+  pub(crate) expose_headers: Vec<String>,
+  #[serde(default)]
+  pub(crate) credentials: bool,
+  pub(crate) max_age: Option<u64>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct UpstreamConfig {
   pub(crate) id: String,
   pub(crate) addrs: Vec<SocketAddr>,
+  /// Members not listed have a weight of `1`.
+  #[serde(default)]
+  pub(crate) weights: HashMap<SocketAddr, u32>,
+  /// Members that receive no requests.
+  #[serde(default)]
+  pub(crate) disabled: Vec<SocketAddr>,
   pub(crate) sni: Option<String>,
+  #[serde(default)]
+  pub(crate) host_header: HostHeaderConfig,
+  pub(crate) concurrency: Option<ConcurrencyConfig>,
+}
+
+impl UpstreamConfig {
+  /// `weights` and `disabled` may only refer to members listed in `addrs`.
+  pub(crate) fn validate(&self) -> Result<(), String> {
+    let unknown = self
+      .weights
+      .keys()
+      .chain(&self.disabled)
+      .find(|addr| !self.addrs.contains(addr));
+
+    match unknown {
+      Some(addr) => Err(format!(
+        "upstream {} references {}, which is not in addrs",
+        self.id, addr
+      )),
+      None => Ok(()),
+    }
+  }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HostHeaderConfig {
+  #[default]
+  Preserve,
+  Sni,
+  Fixed(String),
 }
 
 #[derive(Deserialize)]
@@ -55,3 +547,180 @@ pub(crate) struct CertificateConfig {
   pub(crate) chain: String,
   pub(crate) key: String,
 }
+
+#[derive(Deserialize)]
+pub(crate) struct MaintenanceConfig {
+  pub(crate) id: String,
+  #[serde(default)]
+  pub(crate) enabled: bool,
+  pub(crate) flag: Option<String>,
+  pub(crate) retry_after: Option<u64>,
+  pub(crate) page: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ErrorPagesConfig {
+  pub(crate) id: String,
+  pub(crate) pages: Vec<ErrorPageConfig>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ErrorPageConfig {
+  pub(crate) status: StatusRangeConfig,
+  pub(crate) file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum StatusRangeConfig {
+  Code(u16),
+  Range(String),
+}
+
+fn default_redirect_code() -> u16 {
+  302
+}
+
+fn default_respond_status() -> u16 {
+  200
+}
+
+fn default_metrics_path() -> String {
+  "/metrics".to_string()
+}
+
+fn default_service_name() -> String {
+  "pux".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+  1.0
+}
+
+fn default_access_log_sample() -> f64 {
+  1.0
+}
+
+fn default_realm() -> String {
+  "pux".to_string()
+}
+
+fn default_forward_auth_path() -> String {
+  "/".to_string()
+}
+
+fn default_forward_auth_request_headers() -> Vec<String> {
+  vec!["authorization".to_string(), "cookie".to_string()]
+}
+
+fn default_jwks_refresh() -> u64 {
+  300
+}
+
+fn default_jwt_algorithms() -> Vec<String> {
+  ["RS256", "ES256", "EdDSA", "HS256"]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_jwt_leeway() -> u64 {
+  60
+}
+
+fn default_rate_limit_period() -> f64 {
+  1.0
+}
+
+fn default_ipv4_prefix() -> u8 {
+  32
+}
+
+fn default_ipv6_prefix() -> u8 {
+  128
+}
+
+fn default_queue_timeout() -> u64 {
+  1000
+}
+
+fn default_min_limit() -> usize {
+  1
+}
+
+fn default_encodings() -> Vec<EncodingConfig> {
+  vec![
+    EncodingConfig::Zstd,
+    EncodingConfig::Brotli,
+    EncodingConfig::Gzip,
+  ]
+}
+
+fn default_compression_min_size() -> u64 {
+  1024
+}
+
+fn default_compression_mime_types() -> Vec<String> {
+  [
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/manifest+json",
+    "application/wasm",
+    "application/xml",
+    "image/svg+xml",
+  ]
+  .map(String::from)
+  .to_vec()
+}
+
+fn default_hsts() -> Option<HstsConfig> {
+  Some(HstsConfig {
+    max_age: default_hsts_max_age(),
+    include_subdomains: false,
+    preload: false,
+  })
+}
+
+fn default_hsts_max_age() -> u64 {
+  31536000
+}
+
+fn default_frame_options() -> Option<String> {
+  Some("SAMEORIGIN".to_string())
+}
+
+fn default_referrer_policy() -> Option<String> {
+  Some("strict-origin-when-cross-origin".to_string())
+}
+
+fn default_cors_methods() -> Vec<String> {
+  ["GET", "HEAD", "POST"].map(String::from).to_vec()
+}
+
+fn default_true() -> bool {
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn upstream(raw: &str) -> UpstreamConfig {
+    serde_yaml::from_str(raw).unwrap()
+  }
+
+  #[test]
+  fn validates_upstream_members() {
+    let valid = upstream(
+      "{ id: api, addrs: [ 127.0.0.1:8000, 127.0.0.1:8001 ], weights: { 127.0.0.1:8000: 2 }, \
+       disabled: [ 127.0.0.1:8001 ] }",
+    );
+    assert!(valid.validate().is_ok());
+
+    let weight = upstream("{ id: api, addrs: [ 127.0.0.1:8000 ], weights: { 127.0.0.1:8001: 2 } }");
+    assert!(weight.validate().is_err());
+
+    let disabled = upstream("{ id: api, addrs: [ 127.0.0.1:8000 ], disabled: [ 127.0.0.1:8001 ] }");
+    assert!(disabled.validate().is_err());
+  }
+}
@@ -5,15 +5,27 @@ use std::sync::Arc;
 
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{self, ProtocolVersion};
 use tokio_rustls::TlsAcceptor;
 use tracing::error;
 
 use crate::config::EntrypointConfig;
 use crate::error::PuxResult;
 use crate::handler::Handler;
+use crate::metrics::METRICS;
 use crate::ServerConfig;
 
+/// Details about the connection a request was received on, available in the request extensions.
+#[derive(Clone)]
+pub(crate) struct ConnInfo {
+  pub(crate) peer_addr: SocketAddr,
+  pub(crate) tls: bool,
+  pub(crate) tls_version: Option<&'static str>,
+  pub(crate) sni: Option<Arc<str>>,
+}
+
 pub(crate) struct Entrypoint {
   id: String,
   listener: TcpListener,
@@ -46,42 +58,52 @@ impl Entrypoint {
     while let Some((stream, peer_addr)) = self.accept_stram().await? {
       stream.set_nodelay(true)?;
 
-      let service = {
-        let handler = self.handler.clone();
-        service_fn(move |req| {
-          let handler = handler.clone();
+      let peer_addr = canonical(peer_addr);
 
-          async move { Ok::<_, Infallible>(handler.handle(peer_addr, req).await) }
-        })
-      };
+      let handler = self.handler.clone();
 
       match &self.tls_acceptor {
         None => {
-          tokio::spawn(async move {
-            let conn = Http::new()
-              .http1_only(true)
-              .serve_connection(stream, service);
-            if let Err(err) = conn.await {
-              error!("Failed to serve connection: {}", err);
-            }
-          });
+          let conn_info = ConnInfo {
+            peer_addr,
+            tls: false,
+            tls_version: None,
+            sni: None,
+          };
+
+          let mut http = Http::new();
+          http.http1_only(true);
+
+          tokio::spawn(serve(stream, http, handler, conn_info));
         }
         Some(tls_acceptor) => {
           let tls_acceptor = tls_acceptor.clone();
+          let id = self.id.clone();
           tokio::spawn(async move {
             let tls_stream = match tls_acceptor.accept(stream).await {
               Ok(tls_stream) => tls_stream,
               Err(err) => {
+                METRICS
+                  .tls_handshake_failures
+                  .with_label_values(&[&id, handshake_failure_reason(&err)])
+                  .inc();
                 error!("Error while tls handshake: {}", err);
                 return;
               }
             };
 
-            let conn = Http::new().serve_connection(tls_stream, service);
+            let conn_info = ConnInfo {
+              peer_addr,
+              tls: true,
+              tls_version: match tls_stream.get_ref().1.protocol_version() {
+                Some(ProtocolVersion::TLSv1_3) => Some("TLSv1.3"),
+                Some(ProtocolVersion::TLSv1_2) => Some("TLSv1.2"),
+                _ => None,
+              },
+              sni: tls_stream.get_ref().1.sni_hostname().map(Arc::from),
+            };
 
-            if let Err(err) = conn.await {
-              error!("Failed to serve connection: {}", err);
-            }
+            serve(tls_stream, Http::new(), handler, conn_info).await;
           });
         }
       }
@@ -95,3 +117,87 @@ impl Entrypoint {
     self.id.as_str()
   }
 }
+
+/// The rustls error variant or the io error kind, without any details to keep the cardinality low.
+/// Unmaps IPv4 peers of dual stack listeners, `::ffff:192.0.2.1` becomes `192.0.2.1` so they
+/// match IPv4 networks.
+fn canonical(peer_addr: SocketAddr) -> SocketAddr {
+  SocketAddr::new(peer_addr.ip().to_canonical(), peer_addr.port())
+}
+
+fn handshake_failure_reason(err: &io::Error) -> &'static str {
+  let tls_err = match err
+    .get_ref()
+    .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+  {
+    Some(tls_err) => tls_err,
+    None => {
+      return match err.kind() {
+        io::ErrorKind::UnexpectedEof => "UnexpectedEof",
+        io::ErrorKind::ConnectionReset => "ConnectionReset",
+        io::ErrorKind::ConnectionAborted => "ConnectionAborted",
+        io::ErrorKind::BrokenPipe => "BrokenPipe",
+        io::ErrorKind::TimedOut => "TimedOut",
+        io::ErrorKind::InvalidData => "InvalidData",
+        _ => "Other",
+      }
+    }
+  };
+
+  match tls_err {
+    rustls::Error::InappropriateMessage { .. } => "InappropriateMessage",
+    rustls::Error::InappropriateHandshakeMessage { .. } => "InappropriateHandshakeMessage",
+    rustls::Error::CorruptMessage => "CorruptMessage",
+    rustls::Error::CorruptMessagePayload(_) => "CorruptMessagePayload",
+    rustls::Error::NoCertificatesPresented => "NoCertificatesPresented",
+    rustls::Error::UnsupportedNameType => "UnsupportedNameType",
+    rustls::Error::DecryptError => "DecryptError",
+    rustls::Error::EncryptError => "EncryptError",
+    rustls::Error::PeerIncompatibleError(_) => "PeerIncompatibleError",
+    rustls::Error::PeerMisbehavedError(_) => "PeerMisbehavedError",
+    rustls::Error::AlertReceived(_) => "AlertReceived",
+    rustls::Error::InvalidCertificateEncoding => "InvalidCertificateEncoding",
+    rustls::Error::InvalidCertificateSignatureType => "InvalidCertificateSignatureType",
+    rustls::Error::InvalidCertificateSignature => "InvalidCertificateSignature",
+    rustls::Error::InvalidCertificateData(_) => "InvalidCertificateData",
+    rustls::Error::InvalidSct(_) => "InvalidSct",
+    rustls::Error::General(_) => "General",
+    rustls::Error::FailedToGetCurrentTime => "FailedToGetCurrentTime",
+    rustls::Error::FailedToGetRandomBytes => "FailedToGetRandomBytes",
+    rustls::Error::HandshakeNotComplete => "HandshakeNotComplete",
+    rustls::Error::PeerSentOversizedRecord => "PeerSentOversizedRecord",
+    rustls::Error::NoApplicationProtocol => "NoApplicationProtocol",
+    rustls::Error::BadMaxFragmentSize => "BadMaxFragmentSize",
+  }
+}
+
+async fn serve<S>(stream: S, http: Http, handler: Arc<Handler>, conn_info: ConnInfo)
+where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let service = service_fn(move |req| {
+    let handler = handler.clone();
+    let conn_info = conn_info.clone();
+
+    async move { Ok::<_, Infallible>(handler.handle(conn_info, req).await) }
+  });
+
+  if let Err(err) = http.serve_connection(stream, service).await {
+    error!("Failed to serve connection: {}", err);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unmaps_ipv4_peers() {
+    let peer_addr = canonical("[::ffff:10.99.0.5]:4711".parse().unwrap());
+    assert_eq!(peer_addr, "10.99.0.5:4711".parse().unwrap());
+    assert!(peer_addr.ip().is_ipv4());
+
+    let peer_addr = "[2001:db8::1]:4711".parse().unwrap();
+    assert_eq!(canonical(peer_addr), peer_addr);
+  }
+}
@@ -1,7 +1,7 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::io::Error;
 
-use hyper::{http, StatusCode};
+use hyper::{http, HeaderMap, StatusCode};
 
 pub(crate) type PuxResult<T> = Result<T, PuxError>;
 
@@ -10,6 +10,10 @@ pub(crate) enum PuxError {
   Http(http::Error),
   Hyper(hyper::Error),
   Status(StatusCode),
+  /// Like [`PuxError::Status`] but the headers are added to the error page, e.g. `Retry-After`.
+  StatusWithHeaders(StatusCode, HeaderMap),
+  /// The configuration can not be loaded, e.g. a referenced file is missing.
+  Config(String),
 }
 
 impl Display for PuxError {
@@ -18,7 +22,8 @@ impl Display for PuxError {
       Self::Io(err) => write!(f, "IO Error: {}", err),
       Self::Http(err) => write!(f, "Http Error: {}", err),
       Self::Hyper(err) => write!(f, "Hyper Error: {}", err),
-      Self::Status(code) => write!(
+      Self::Config(err) => write!(f, "Configuration Error: {}", err),
+      Self::Status(code) | Self::StatusWithHeaders(code, _) => write!(
         f,
         "Status Code: {} {}",
         code.as_u16(),
@@ -0,0 +1,310 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use hyper::header::{CONTENT_TYPE, SERVER};
+use hyper::http::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+use mime::{APPLICATION_JSON, TEXT_HTML_UTF_8, TEXT_PLAIN_UTF_8};
+use serde_json::json;
+use tracing::error;
+
+const ERROR_PAGE: &str = include_str!("error.html");
+
+/// Html templates for error responses, selected by the narrowest matching status range.
+pub(crate) struct ErrorPages(Vec<(RangeInclusive<u16>, String)>);
+
+pub(crate) struct ErrorDetails<'a> {
+  pub(crate) code: StatusCode,
+  pub(crate) peer_addr: IpAddr,
+  pub(crate) host: &'a str,
+  pub(crate) request_id: &'a str,
+  pub(crate) elapsed: Duration,
+}
+
+enum Format {
+  Html,
+  Json,
+  Text,
+}
+
+impl ErrorPages {
+  pub(crate) fn new() -> Self {
+    Self(Vec::new())
+  }
+
+  pub(crate) fn insert(&mut self, codes: RangeInclusive<u16>, template: String) {
+    self.0.push((codes, template));
+  }
+
+  fn find(&self, code: StatusCode) -> Option<&str> {
+    self
+      .0
+      .iter()
+      .filter(|(codes, _)| codes.contains(&code.as_u16()))
+      .min_by_key(|(codes, _)| codes.end() - codes.start())
+      .map(|(_, template)| template.as_str())
+  }
+}
+
+/// Parses `404`, `5xx` or `500-504` into a range of status codes.
+pub(crate) fn parse_status_range(raw: &str) -> Option<RangeInclusive<u16>> {
+  let raw = raw.trim();
+
+  if let Some(class) = raw.strip_suffix("xx") {
+    let class = class
+      .parse::<u16>()
+      .ok()
+      .filter(|class| (1..=5).contains(class))?;
+    return Some(class * 100..=class * 100 + 99);
+  }
+
+  match raw.split_once('-') {
+    Some((start, end)) => {
+      let start = start.trim().parse().ok()?;
+      let end = end.trim().parse().ok()?;
+      (start <= end).then_some(start..=end)
+    }
+    None => raw.parse().ok().map(|code| code..=code),
+  }
+}
+
+/// Renders the error in the format preferred by the client, html pages are taken from the first
+/// of the supplied page sets that contains one for the status code.
+pub(crate) fn error_page(
+  details: ErrorDetails,
+  accept: Option<&HeaderValue>,
+  pages: &[Option<&ErrorPages>],
+) -> Response<Body> {
+  let (content_type, body) = match negotiate(accept) {
+    Format::Html => {
+      let template = pages
+        .iter()
+        .flatten()
+        .find_map(|pages| pages.find(details.code))
+        .unwrap_or(ERROR_PAGE);
+      (TEXT_HTML_UTF_8, render_html(template, &details))
+    }
+    Format::Json => (APPLICATION_JSON, render_json(&details)),
+    Format::Text => (TEXT_PLAIN_UTF_8, render_text(&details)),
+  };
+
+  let result = Response::builder()
+    .status(details.code)
+    .header(CONTENT_TYPE, content_type.as_ref())
+    .header(SERVER, "pux")
+    .body(body.into());
+
+  match result {
+    Ok(resp) => resp,
+    Err(err) => {
+      error!("Fatal error while creating error page: {}", err);
+      let mut response = Response::new("Fatal Error".into());
+      *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+      response
+    }
+  }
+}
+
+fn render_html(template: &str, details: &ErrorDetails) -> String {
+  let mut page = String::with_capacity(template.len());
+  let mut rest = template;
+
+  while let Some(start) = rest.find("{{") {
+    page.push_str(&rest[..start]);
+    rest = &rest[start..];
+
+    let value = rest.find("}}").and_then(|end| {
+      let value = match &rest[2..end] {
+        "CODE" => details.code.as_str().to_string(),
+        "REASON" => details.code.canonical_reason().unwrap_or("").to_string(),
+        "PEER_ADDR" => details.peer_addr.to_string(),
+        "HOST" => details.host.to_string(),
+        "REQUEST_ID" => details.request_id.to_string(),
+        "ELAPSED" => format!("{:?}", details.elapsed),
+        _ => return None,
+      };
+      Some((value, end + 2))
+    });
+
+    match value {
+      Some((value, end)) => {
+        escape_html(&mut page, &value);
+        rest = &rest[end..];
+      }
+      None => {
+        page.push_str("{{");
+        rest = &rest[2..];
+      }
+    }
+  }
+
+  page.push_str(rest);
+  page
+}
+
+fn render_json(details: &ErrorDetails) -> String {
+  json!({
+    "code": details.code.as_u16(),
+    "reason": details.code.canonical_reason().unwrap_or(""),
+    "peer_addr": details.peer_addr.to_string(),
+    "host": details.host,
+    "request_id": details.request_id,
+    "elapsed": format!("{:?}", details.elapsed),
+  })
+  .to_string()
+}
+
+fn render_text(details: &ErrorDetails) -> String {
+  format!(
+    "{} {}\n\n- Your IP: {}\n- Host: {}\n- Request ID: {}\n- Took: {:?}\n",
+    details.code.as_str(),
+    details.code.canonical_reason().unwrap_or(""),
+    details.peer_addr,
+    details.host,
+    details.request_id,
+    details.elapsed
+  )
+}
+
+fn escape_html(out: &mut String, value: &str) {
+  for c in value.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&#x27;"),
+      c => out.push(c),
+    }
+  }
+}
+
+/// Picks the supported format with the highest quality from the accept header, browsers and
+/// clients without preference get html.
+fn negotiate(accept: Option<&HeaderValue>) -> Format {
+  let accept = match accept.and_then(|accept| accept.to_str().ok()) {
+    Some(accept) => accept,
+    None => return Format::Html,
+  };
+
+  let mut best: Option<(Format, f32)> = None;
+
+  for range in accept.split(',') {
+    let mut params = range.split(';');
+
+    let format = match params.next().map(|media| media.trim().to_ascii_lowercase()) {
+      Some(media) => match media.as_str() {
+        "text/html" | "text/*" | "*/*" => Format::Html,
+        "application/json" | "application/problem+json" => Format::Json,
+        "text/plain" => Format::Text,
+        _ => continue,
+      },
+      None => continue,
+    };
+
+    let quality = params
+      .filter_map(|param| param.trim().strip_prefix("q="))
+      .find_map(|quality| quality.parse::<f32>().ok())
+      .unwrap_or(1.0);
+
+    if quality > 0.0 && best.as_ref().is_none_or(|(_, best)| quality > *best) {
+      best = Some((format, quality));
+    }
+  }
+
+  best.map(|(format, _)| format).unwrap_or(Format::Html)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn details(host: &str) -> ErrorDetails<'_> {
+    ErrorDetails {
+      code: StatusCode::NOT_FOUND,
+      peer_addr: IpAddr::from([192, 0, 2, 1]),
+      host,
+      request_id: "abc",
+      elapsed: Duration::from_millis(3),
+    }
+  }
+
+  fn negotiated(accept: &'static str) -> Format {
+    negotiate(Some(&HeaderValue::from_static(accept)))
+  }
+
+  #[test]
+  fn escapes_reflected_values() {
+    let page = render_html(
+      "<h1>{{CODE}} {{REASON}}</h1><p>{{HOST}}</p>{{UNKNOWN}} {{",
+      &details("<script>alert('x')</script>\"&"),
+    );
+    assert_eq!(
+      page,
+      "<h1>404 Not Found</h1>\
+       <p>&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt;&quot;&amp;</p>{{UNKNOWN}} {{"
+    );
+  }
+
+  #[test]
+  fn negotiates_format() {
+    assert!(matches!(negotiate(None), Format::Html));
+    assert!(matches!(
+      negotiated("text/html,application/xhtml+xml,*/*;q=0.8"),
+      Format::Html
+    ));
+    assert!(matches!(negotiated("application/json"), Format::Json));
+    assert!(matches!(
+      negotiated("application/problem+json"),
+      Format::Json
+    ));
+    assert!(matches!(negotiated("text/plain"), Format::Text));
+    assert!(matches!(
+      negotiated("text/html;q=0.5, application/json;q=0.9"),
+      Format::Json
+    ));
+    assert!(matches!(
+      negotiated("application/json;q=0, text/plain"),
+      Format::Text
+    ));
+    assert!(matches!(negotiated("image/png"), Format::Html));
+  }
+
+  #[test]
+  fn renders_negotiated_content_type() {
+    let accept = HeaderValue::from_static("application/json");
+    let resp = error_page(details("example.com"), Some(&accept), &[]);
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+  }
+
+  #[test]
+  fn parses_status_ranges() {
+    assert_eq!(parse_status_range("404"), Some(404..=404));
+    assert_eq!(parse_status_range("5xx"), Some(500..=599));
+    assert_eq!(parse_status_range(" 500 - 504 "), Some(500..=504));
+    assert_eq!(parse_status_range("504-500"), None);
+    assert_eq!(parse_status_range("6xx"), None);
+    assert_eq!(parse_status_range("abc"), None);
+  }
+
+  #[test]
+  fn finds_narrowest_range() {
+    let mut pages = ErrorPages::new();
+    pages.insert(500..=599, "server".to_string());
+    pages.insert(502..=504, "gateway".to_string());
+    pages.insert(503..=503, "unavailable".to_string());
+
+    assert_eq!(
+      pages.find(StatusCode::SERVICE_UNAVAILABLE),
+      Some("unavailable")
+    );
+    assert_eq!(pages.find(StatusCode::BAD_GATEWAY), Some("gateway"));
+    assert_eq!(
+      pages.find(StatusCode::INTERNAL_SERVER_ERROR),
+      Some("server")
+    );
+    assert_eq!(pages.find(StatusCode::NOT_FOUND), None);
+  }
+}
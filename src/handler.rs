@@ -1,106 +1,248 @@
-use std::net::{IpAddr, SocketAddr};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 
-use hyper::header::{CONTENT_TYPE, HOST, SERVER};
+use hyper::header::{HeaderName, ACCEPT, HOST, SERVER};
+use hyper::http::uri::Authority;
 use hyper::http::HeaderValue;
-use hyper::{Body, Request, Response, StatusCode};
-use mime::TEXT_HTML_UTF_8;
-use tracing::{error, warn};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use ipnet::IpNet;
+use tracing::field::Empty;
+use tracing::{info_span, warn, Instrument};
+use uuid::Uuid;
 
-use crate::error::PuxError::Status;
-use crate::routes::Routes;
+use crate::access_log::{self, AccessLog};
+use crate::entrypoint::ConnInfo;
+use crate::error::PuxError::{Status, StatusWithHeaders};
+use crate::error_page::{error_page, ErrorDetails, ErrorPages};
+use crate::metrics::{status_class, METRICS};
+use crate::routes::{Route, Routes};
+use crate::service::redirect::RedirectService;
+use crate::service::Service;
+use crate::telemetry;
+use crate::upstream::UpstreamInfo;
 
-const ERROR_PAGE: &str = include_str!("error.html");
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Identifies a request across pux, the upstream and the client, stored as request extension.
+#[derive(Clone)]
+pub(crate) struct RequestId(pub(crate) HeaderValue);
 
 pub(crate) struct Handler {
+  entrypoint: String,
   routes: Routes,
+  https_redirect: Option<RedirectService>,
+  error_pages: Option<Arc<ErrorPages>>,
+  /// Peers allowed to supply their own `X-Request-Id`.
+  trusted_request_ids: Vec<IpNet>,
+  access_log: Option<Arc<AccessLog>>,
 }
 
 impl Handler {
-  pub(crate) fn new(routes: Routes) -> Self {
-    Self { routes }
+  pub(crate) fn new(
+    entrypoint: String,
+    routes: Routes,
+    https_redirect: Option<RedirectService>,
+    error_pages: Option<Arc<ErrorPages>>,
+    trusted_request_ids: Vec<IpNet>,
+    access_log: Option<Arc<AccessLog>>,
+  ) -> Self {
+    Self {
+      entrypoint,
+      routes,
+      https_redirect,
+      error_pages,
+      trusted_request_ids,
+      access_log,
+    }
+  }
+
+  pub(crate) fn routes(&self) -> &Routes {
+    &self.routes
+  }
+
+  /// The incoming id if the peer is trusted and the id is sane, a new random one otherwise.
+  fn request_id(&self, req: &Request<Body>, conn_info: &ConnInfo) -> HeaderValue {
+    let peer = conn_info.peer_addr.ip();
+    let trusted = self
+      .trusted_request_ids
+      .iter()
+      .any(|net| net.contains(&peer));
+
+    match req.headers().get(X_REQUEST_ID) {
+      Some(id)
+        if trusted
+          && !id.is_empty()
+          && id.len() <= MAX_REQUEST_ID_LEN
+          && id.as_bytes().iter().all(u8::is_ascii_graphic) =>
+      {
+        id.clone()
+      }
+      _ => HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap(),
+    }
   }
 }
 
 impl Handler {
-  pub(crate) async fn handle(&self, peer_addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+  pub(crate) async fn handle(&self, conn_info: ConnInfo, mut req: Request<Body>) -> Response<Body> {
     let start = Instant::now();
 
-    let host = req
-      .headers()
-      .get(HOST)
-      .and_then(|raw| raw.to_str().ok())
-      .map(|with_port| {
-        with_port
-          .rfind(':')
-          .map(|index| &with_port[..index])
-          .unwrap_or(with_port)
-      })
-      .or_else(|| req.uri().host()) // http2 does not contain a host header?? but in the uri it is included??
-      .map(|host| host.to_string());
-
-    let service = match host {
-      Some(ref host) => {
-        let path = req.uri().path().split('/').collect::<Vec<&str>>();
-        self.routes.find(host, &path)
+    let request_id = self.request_id(&req, &conn_info);
+    let peer_addr = conn_info.peer_addr;
+    req.headers_mut().insert(X_REQUEST_ID, request_id.clone());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    req.extensions_mut().insert(conn_info);
+
+    let entry = self
+      .access_log
+      .as_ref()
+      .map(|_| access_log::Entry::new(&req, start));
+
+    let method = req.method().clone();
+    let span = info_span!(
+      "request",
+      id = request_id.to_str().unwrap_or_default(),
+      otel.kind = "server",
+      otel.name = method.as_str(),
+      otel.status_code = Empty,
+      http.request.method = method.as_str(),
+      url.path = req.uri().path(),
+      server.address = request_host(&req).unwrap_or_default(),
+      http.route = Empty,
+      http.response.status_code = Empty,
+    );
+    telemetry::set_parent(&span, req.headers());
+
+    let (route, mut resp) = self
+      .respond(req, peer_addr, start, &request_id)
+      .instrument(span.clone())
+      .await;
+
+    let route = route.map(|route| route.name.as_str()).unwrap_or("-");
+    span.record("otel.name", format!("{} {}", method, route));
+    span.record("http.route", route);
+    span.record("http.response.status_code", resp.status().as_u16());
+    if resp.status().is_server_error() {
+      span.record("otel.status_code", "ERROR");
+    }
+    METRICS
+      .requests
+      .with_label_values(&[&self.entrypoint, route, status_class(resp.status())])
+      .inc();
+    METRICS
+      .request_duration
+      .with_label_values(&[&self.entrypoint, route])
+      .observe(start.elapsed().as_secs_f64());
+
+    resp.headers_mut().insert(X_REQUEST_ID, request_id);
+    match (&self.access_log, entry) {
+      (Some(access_log), Some(entry)) => access_log.log(entry, resp),
+      _ => resp,
+    }
+  }
+
+  async fn respond(
+    &self,
+    mut req: Request<Body>,
+    peer_addr: SocketAddr,
+    start: Instant,
+    request_id: &HeaderValue,
+  ) -> (Option<&Route>, Response<Body>) {
+    // cloning shares the buffers of the request, which is moved into the route
+    let host_header = req.headers().get(HOST).cloned();
+    let authority = req.uri().authority().cloned();
+    let host = host_of(host_header.as_ref(), authority.as_ref());
+    let accept = req.headers().get(ACCEPT).cloned();
+
+    let (route, result) = match &self.https_redirect {
+      // acme http-01 challenges have to be answered in plaintext
+      Some(redirect) if !req.uri().path().starts_with(ACME_CHALLENGE_PATH) => {
+        (None, Some(redirect.handle(req).await))
       }
-      None => None,
-    };
+      _ => {
+        let route = match host {
+          Some(host) => self.routes.find(host, req.uri().path(), &req),
+          None => None,
+        };
 
-    let code = match service {
-      None => StatusCode::NOT_FOUND,
-      Some(service) => match service.handle(req).await {
-        Ok(mut resp) => {
-          resp
-            .headers_mut()
-            .insert(SERVER, HeaderValue::from_static("pux"));
-          return resp;
+        match route {
+          Some((route, params)) => {
+            req.extensions_mut().insert(params);
+            (Some(route), Some(route.handle(req).await))
+          }
+          None => (None, None),
         }
-        Err(Status(code)) => code,
-        Err(err) => {
-          warn!("Handled error while handling request: {}", err);
-          StatusCode::INTERNAL_SERVER_ERROR
-        }
-      },
+      }
     };
 
-    let elapsed = start.elapsed();
+    let mut headers = HeaderMap::new();
+
+    let code = match result {
+      None => StatusCode::NOT_FOUND,
+      // only upstream responses, pages pux generated itself like maintenance are kept
+      Some(Ok(resp))
+        if resp.status().is_server_error()
+          && resp.extensions().get::<UpstreamInfo>().is_some()
+          && route.is_some_and(|route| route.intercept_errors) =>
+      {
+        resp.status()
+      }
+      Some(Ok(mut resp)) => {
+        resp
+          .headers_mut()
+          .insert(SERVER, HeaderValue::from_static("pux"));
+        return (route, resp);
+      }
+      Some(Err(Status(code))) => code,
+      Some(Err(StatusWithHeaders(code, extra))) => {
+        headers = extra;
+        code
+      }
+      Some(Err(err)) => {
+        warn!("Handled error while handling request: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+      }
+    };
 
-    error_page(
+    let details = ErrorDetails {
       code,
-      peer_addr.ip(),
-      host.unwrap_or_else(|| "unknown".to_string()),
-      elapsed,
-    )
+      peer_addr: peer_addr.ip(),
+      host: host.unwrap_or("unknown"),
+      request_id: request_id.to_str().unwrap_or_default(),
+      elapsed: start.elapsed(),
+    };
+
+    let mut resp = error_page(
+      details,
+      accept.as_ref(),
+      &[
+        route.and_then(|route| route.error_pages.as_deref()),
+        self.error_pages.as_deref(),
+      ],
+    );
+    resp.headers_mut().extend(headers);
+    (route, resp)
   }
 }
 
-fn error_page(
-  code: StatusCode,
-  peer_addr: IpAddr,
-  host: String,
-  elapsed: Duration,
-) -> Response<Body> {
-  let page = ERROR_PAGE
-    .replace("{{CODE}}", code.as_str())
-    .replace("{{REASON}}", code.canonical_reason().unwrap_or(""))
-    .replace("{{PEER_ADDR}}", &format!("{}", peer_addr))
-    .replace("{{HOST}}", &host)
-    .replace("{{ELAPSED}}", &format!("{:?}", elapsed));
-
-  let result = Response::builder()
-    .status(code)
-    .header(CONTENT_TYPE, TEXT_HTML_UTF_8.as_ref())
-    .header(SERVER, "pux")
-    .body(page.into());
-
-  match result {
-    Ok(resp) => resp,
-    Err(err) => {
-      error!("Fatal error while creating error page: {}", err);
-      let mut response = Response::new("Fatal Error".into());
-      *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-      response
-    }
-  }
+/// Host of the request without the port, taken from the host header or the uri.
+pub(crate) fn request_host<T>(req: &Request<T>) -> Option<&str> {
+  host_of(req.headers().get(HOST), req.uri().authority())
+}
+
+pub(crate) fn host_of<'a>(
+  header: Option<&'a HeaderValue>,
+  authority: Option<&'a Authority>,
+) -> Option<&'a str> {
+  header
+    .and_then(|raw| raw.to_str().ok())
+    .map(|with_port| {
+      with_port
+        .rfind(':')
+        .map(|index| &with_port[..index])
+        .unwrap_or(with_port)
+    })
+    .or_else(|| authority.map(Authority::host)) // http2 does not contain a host header?? but in the uri it is included??
 }
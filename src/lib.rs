@@ -0,0 +1,5 @@
+//! Route lookup, a library so the benchmarks can use it as well.
+
+pub mod path;
+pub mod router;
+pub mod template;
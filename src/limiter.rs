@@ -0,0 +1,269 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_util::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Response};
+use pin_project::pin_project;
+use tokio::sync::Notify;
+use tokio::time::timeout_at;
+use tracing::debug;
+
+/// Caps the number of in-flight requests, further requests wait in a bounded queue for at most
+/// `queue_timeout` and are rejected otherwise.
+pub(crate) struct Limiter {
+  state: Mutex<State>,
+  notify: Notify,
+  max_queue: usize,
+  queue_timeout: Duration,
+  adaptive: Option<Adaptive>,
+}
+
+struct State {
+  limit: usize,
+  in_flight: usize,
+  queued: usize,
+}
+
+/// Additive increase, multiplicative decrease of the limit based on the observed latency.
+pub(crate) struct Adaptive {
+  pub(crate) min_limit: usize,
+  pub(crate) max_limit: usize,
+  /// Completions slower than this (or failed ones) shrink the limit.
+  pub(crate) latency: Duration,
+}
+
+pub(crate) struct Permit {
+  limiter: Arc<Limiter>,
+  started: Instant,
+  /// Time until the response headers, the body may be streamed for much longer.
+  latency: Option<Duration>,
+  success: bool,
+}
+
+/// Holds the permit until the response body is fully sent or dropped.
+#[pin_project]
+struct Guarded {
+  #[pin]
+  body: Body,
+  permit: Option<Permit>,
+}
+
+const BACKOFF: f64 = 0.9;
+
+impl Limiter {
+  pub(crate) fn new(
+    limit: usize,
+    max_queue: usize,
+    queue_timeout: Duration,
+    adaptive: Option<Adaptive>,
+  ) -> Self {
+    Self {
+      state: Mutex::new(State {
+        limit,
+        in_flight: 0,
+        queued: 0,
+      }),
+      notify: Notify::new(),
+      max_queue,
+      queue_timeout,
+      adaptive,
+    }
+  }
+
+  /// Waits for a free slot, `None` if the queue is full or the wait timed out.
+  pub(crate) async fn acquire(self: &Arc<Self>) -> Option<Permit> {
+    let deadline = tokio::time::Instant::now() + self.queue_timeout;
+    let mut queued = false;
+
+    let acquired = loop {
+      let notified = self.notify.notified();
+      tokio::pin!(notified);
+      notified.as_mut().enable();
+
+      {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight < state.limit {
+          state.in_flight += 1;
+          if queued {
+            state.queued -= 1;
+          }
+          break true;
+        }
+
+        if !queued {
+          if state.queued >= self.max_queue {
+            break false;
+          }
+          state.queued += 1;
+          queued = true;
+        }
+      }
+
+      if timeout_at(deadline, notified).await.is_err() {
+        // a slot may have been freed right as the wait timed out, its wakeup went to us
+        let mut state = self.state.lock().unwrap();
+        state.queued -= 1;
+        if state.in_flight < state.limit {
+          state.in_flight += 1;
+          break true;
+        }
+        break false;
+      }
+    };
+
+    if !acquired {
+      debug!("Shedding request, concurrency limit reached");
+      return None;
+    }
+
+    Some(Permit {
+      limiter: self.clone(),
+      started: Instant::now(),
+      latency: None,
+      success: true,
+    })
+  }
+
+  fn release(&self, latency: Duration, success: bool) {
+    let mut state = self.state.lock().unwrap();
+    state.in_flight -= 1;
+
+    if let Some(adaptive) = &self.adaptive {
+      let limit = if !success || latency > adaptive.latency {
+        ((state.limit as f64 * BACKOFF) as usize).max(adaptive.min_limit)
+      } else if state.in_flight + 1 >= state.limit {
+        // only grow while the limit is actually reached
+        (state.limit + 1).min(adaptive.max_limit)
+      } else {
+        state.limit
+      };
+
+      if limit != state.limit {
+        debug!("Adjusting concurrency limit to {}", limit);
+        state.limit = limit;
+      }
+    }
+
+    drop(state);
+    self.notify.notify_one();
+  }
+}
+
+impl Permit {
+  /// Records the outcome for an adaptive limit, the slot is released once the permit is dropped.
+  pub(crate) fn complete(&mut self, success: bool) {
+    self.latency = Some(self.started.elapsed());
+    self.success = success;
+  }
+
+  /// Moves the permit into the response body.
+  pub(crate) fn attach(self, mut resp: Response<Body>) -> Response<Body> {
+    if resp.body().is_end_stream() {
+      return resp;
+    }
+
+    let body = std::mem::take(resp.body_mut());
+    *resp.body_mut() = Body::wrap_stream(Guarded {
+      body,
+      permit: Some(self),
+    });
+    resp
+  }
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    let latency = self.latency.unwrap_or_else(|| self.started.elapsed());
+    self.limiter.release(latency, self.success);
+  }
+}
+
+impl Stream for Guarded {
+  type Item = hyper::Result<Bytes>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    let this = self.project();
+    let chunk = ready!(this.body.poll_next(cx));
+    if !matches!(chunk, Some(Ok(_))) {
+      this.permit.take();
+    }
+    Poll::Ready(chunk)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(limit: usize, queue: usize) -> Arc<Limiter> {
+    Arc::new(Limiter::new(limit, queue, Duration::from_millis(50), None))
+  }
+
+  fn in_flight(limiter: &Limiter) -> usize {
+    limiter.state.lock().unwrap().in_flight
+  }
+
+  #[tokio::test]
+  async fn rejects_without_queue() {
+    let limiter = limiter(1, 0);
+    let permit = limiter.acquire().await.unwrap();
+    assert!(limiter.acquire().await.is_none());
+    drop(permit);
+    assert!(limiter.acquire().await.is_some());
+  }
+
+  #[tokio::test]
+  async fn queued_request_gets_released_slot() {
+    let limiter = limiter(1, 1);
+    let permit = limiter.acquire().await.unwrap();
+    let waiting = tokio::spawn({
+      let limiter = limiter.clone();
+      async move { limiter.acquire().await.is_some() }
+    });
+    tokio::task::yield_now().await;
+    drop(permit);
+    assert!(waiting.await.unwrap());
+    assert_eq!(limiter.state.lock().unwrap().queued, 0);
+  }
+
+  #[tokio::test]
+  async fn queue_times_out() {
+    let limiter = limiter(1, 1);
+    let _permit = limiter.acquire().await.unwrap();
+    assert!(limiter.acquire().await.is_none());
+    assert_eq!(limiter.state.lock().unwrap().queued, 0);
+  }
+
+  #[tokio::test]
+  async fn permit_is_held_until_body_ends() {
+    let limiter = limiter(1, 0);
+    let mut permit = limiter.acquire().await.unwrap();
+    permit.complete(true);
+    let mut resp = permit.attach(Response::new(Body::from("body")));
+    assert_eq!(in_flight(&limiter), 1);
+
+    while resp.body_mut().data().await.is_some() {}
+    assert_eq!(in_flight(&limiter), 0);
+  }
+
+  #[tokio::test]
+  async fn permit_is_released_with_dropped_body() {
+    let limiter = limiter(1, 0);
+    let permit = limiter.acquire().await.unwrap();
+    let resp = permit.attach(Response::new(Body::from("body")));
+    assert_eq!(in_flight(&limiter), 1);
+    drop(resp);
+    assert_eq!(in_flight(&limiter), 0);
+  }
+
+  #[tokio::test]
+  async fn permit_is_released_for_empty_body() {
+    let limiter = limiter(1, 0);
+    let permit = limiter.acquire().await.unwrap();
+    let _resp = permit.attach(Response::new(Body::empty()));
+    assert_eq!(in_flight(&limiter), 0);
+  }
+}
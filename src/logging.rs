@@ -0,0 +1,150 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{uptime, FormatTime};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::config::{
+  LogFormatConfig, LogLevelsConfig, LogTimestampsConfig, LoggingConfig, OpenTelemetryConfig,
+};
+use crate::telemetry;
+
+/// The global subscriber, the levels of its log output can be changed while running.
+pub(crate) struct Logging {
+  handle: reload::Handle<EnvFilter, Registry>,
+  /// Directives from the configuration, restored by [`Logging::reset`].
+  configured: String,
+}
+
+struct UnixTime;
+
+impl Logging {
+  pub(crate) fn init(
+    config: &LoggingConfig,
+    opentelemetry: Option<&OpenTelemetryConfig>,
+  ) -> Arc<Self> {
+    let configured = directives(&config.levels).expect("invalid logging configuration");
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&configured));
+
+    // the configured levels only apply to the log output, spans are exported independently
+    tracing_subscriber::registry()
+      .with(fmt_layer(config).with_filter(filter))
+      .with(
+        opentelemetry
+          .map(telemetry::layer)
+          .with_filter(LevelFilter::INFO),
+      )
+      .init();
+
+    Arc::new(Self { handle, configured })
+  }
+
+  /// The active directives, e.g. `info,pux::upstream=debug`.
+  pub(crate) fn current(&self) -> String {
+    self
+      .handle
+      .with_current(ToString::to_string)
+      .unwrap_or_default()
+  }
+
+  pub(crate) fn configured(&self) -> &str {
+    &self.configured
+  }
+
+  pub(crate) fn set(&self, levels: &LogLevelsConfig) -> Result<(), String> {
+    let directives = directives(levels)?;
+    self.apply(&directives);
+    Ok(())
+  }
+
+  pub(crate) fn reset(&self) {
+    self.apply(&self.configured);
+  }
+
+  fn apply(&self, directives: &str) {
+    match self.handle.reload(EnvFilter::new(directives)) {
+      Ok(()) => info!("Changed log levels to {}", directives),
+      Err(err) => warn!("Unable to change log levels: {}", err),
+    }
+  }
+}
+
+/// Switches between `debug` for every target and the configured levels on `SIGUSR2`.
+pub(crate) fn toggle_debug_on_signal(logging: Arc<Logging>) {
+  #[cfg(unix)]
+  tokio::spawn(async move {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())
+      .expect("failed to install signal handler");
+    while signal.recv().await.is_some() {
+      if logging.current() == "debug" {
+        logging.reset();
+      } else {
+        logging.apply("debug");
+      }
+    }
+  });
+
+  #[cfg(not(unix))]
+  drop(logging);
+}
+
+/// Validates the levels, a bare word would otherwise be taken as a target.
+fn directives(levels: &LogLevelsConfig) -> Result<String, String> {
+  let parse = |level: &str| {
+    level
+      .parse::<LevelFilter>()
+      .map_err(|_| format!("invalid log level {}", level))
+  };
+
+  let mut directives = vec![parse(levels.level.as_deref().unwrap_or("info"))?.to_string()];
+  let mut targets: Vec<_> = levels.targets.iter().collect();
+  targets.sort();
+  for (target, level) in targets {
+    if target.is_empty() || target.contains([',', '=', '[', ' ']) {
+      return Err(format!("invalid log target {}", target));
+    }
+    directives.push(format!("{}={}", target, parse(level)?));
+  }
+
+  Ok(directives.join(",").to_lowercase())
+}
+
+fn fmt_layer<S>(config: &LoggingConfig) -> Box<dyn Layer<S> + Send + Sync>
+where
+  S: Subscriber + for<'span> LookupSpan<'span>,
+{
+  let text = tracing_subscriber::fmt::layer();
+  let json = tracing_subscriber::fmt::layer().json().flatten_event(true);
+
+  match (&config.format, &config.timestamps) {
+    (LogFormatConfig::Text, LogTimestampsConfig::Rfc3339) => text.boxed(),
+    (LogFormatConfig::Text, LogTimestampsConfig::Unix) => text.with_timer(UnixTime).boxed(),
+    (LogFormatConfig::Text, LogTimestampsConfig::Uptime) => text.with_timer(uptime()).boxed(),
+    (LogFormatConfig::Text, LogTimestampsConfig::None) => text.without_time().boxed(),
+    (LogFormatConfig::Json, LogTimestampsConfig::Rfc3339) => json.boxed(),
+    (LogFormatConfig::Json, LogTimestampsConfig::Unix) => json.with_timer(UnixTime).boxed(),
+    (LogFormatConfig::Json, LogTimestampsConfig::Uptime) => json.with_timer(uptime()).boxed(),
+    (LogFormatConfig::Json, LogTimestampsConfig::None) => json.without_time().boxed(),
+  }
+}
+
+impl FormatTime for UnixTime {
+  fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+    let since_epoch = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    write!(
+      w,
+      "{}.{:03}",
+      since_epoch.as_secs(),
+      since_epoch.subsec_millis()
+    )
+  }
+}
@@ -1,7 +1,20 @@
 use std::collections::HashMap;
 use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use ::pux::{path, router, template};
+use hyper::header::{
+  HeaderName, CONTENT_SECURITY_POLICY, CONTENT_TYPE, REFERRER_POLICY, RETRY_AFTER,
+  X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use hyper::http::{HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode, Uri};
+use ipnet::IpNet;
+use mime::TEXT_HTML_UTF_8;
+use regex::Regex;
 use tokio::signal::ctrl_c;
 use tokio::{select, signal};
 use tokio_rustls::rustls::client::ServerName;
@@ -10,37 +23,78 @@ use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::webpki::DnsNameRef;
 use tracing::{error, info};
 
+use crate::access_log::{reopen_on_signal, AccessLog, Format};
+use crate::admin::{Admin, ConfigInfo, EntrypointInfo, ServiceInfo};
 use crate::cert::{load_certs, load_private_key, CertStore};
-use crate::config::{CertificateConfig, Config};
+use crate::config::{
+  AccessLogFormatConfig, CertificateConfig, ConcurrencyConfig, Config, EncodingConfig,
+  ErrorPagesConfig, HeaderRulesConfig, HostHeaderConfig, MaintenanceConfig, MatchConfig,
+  PathConfig, RateLimitKeyConfig, RespondServiceConfig, RouteConfig, StatusRangeConfig,
+  ValueMatchConfig,
+};
 use crate::entrypoint::Entrypoint;
-use crate::error::PuxResult;
+use crate::error::{PuxError, PuxResult};
+use crate::error_page::{parse_status_range, ErrorPages};
 use crate::handler::Handler;
+use crate::limiter::{Adaptive, Limiter};
+use crate::logging::{toggle_debug_on_signal, Logging};
+use crate::maintenance::Maintenance;
+use crate::matcher::{Matcher, ValueMatcher};
+use crate::metrics::Exporter;
+use crate::middleware::basic_auth::{parse_htpasswd, BasicAuth};
+use crate::middleware::compression::{Compression, Encoding};
+use crate::middleware::concurrency_limit::ConcurrencyLimit;
+use crate::middleware::cors::{AllowedOrigin, Cors};
+use crate::middleware::forward_auth::ForwardAuth;
+use crate::middleware::headers::{HeaderRules, Headers};
+use crate::middleware::ip_filter::{parse_net, IpFilter, NetList};
+use crate::middleware::jwt::{JwksSource, Jwt};
+use crate::middleware::rate_limit::{RateLimit, RateLimitKey};
+use crate::middleware::security_headers::SecurityHeaders;
+use crate::middleware::Middleware;
+use crate::path::PathPattern;
 use crate::pux::Pux;
-use crate::routes::Routes;
+use crate::rewrite::Rewrite;
+use crate::routes::{Route, RoutesBuilder};
 use crate::service::proxy::ProxyService;
+use crate::service::redirect::RedirectService;
+use crate::service::respond::RespondService;
 use crate::service::Service;
-use crate::upstream::Upstream;
+use crate::upstream::{HostHeader, MemberState, Upstream};
 
+mod access_log;
+mod admin;
 mod cert;
 mod config;
 mod entrypoint;
 mod error;
+mod error_page;
 mod handler;
+mod limiter;
+mod logging;
+mod maintenance;
+mod matcher;
+mod metrics;
+mod middleware;
 mod pux;
+mod rewrite;
 mod routes;
 mod service;
+mod telemetry;
 mod upstream;
 
 #[tokio::main]
 async fn main() -> PuxResult<()> {
-  tracing_subscriber::fmt::init();
-
   let config_path = std::env::current_dir()?.join("config.yaml");
 
   let config: Config = {
     let config = File::open(&config_path).unwrap();
     serde_yaml::from_reader(config).unwrap()
   };
+  let loaded_at = SystemTime::now();
+
+  let logging = Logging::init(&config.logging, config.opentelemetry.as_ref());
+  toggle_debug_on_signal(logging.clone());
 
   let cert_store = Arc::new(build_cert_store(config.certs));
 
@@ -48,44 +102,436 @@ async fn main() -> PuxResult<()> {
 
   let mut upstreams = HashMap::new();
   for conf in config.upstreams {
+    conf.validate().map_err(PuxError::Config)?;
+    let host_header = match conf.host_header {
+      HostHeaderConfig::Preserve => HostHeader::Preserve,
+      HostHeaderConfig::Sni => {
+        let sni = conf.sni.as_deref().ok_or_else(|| {
+          PuxError::Config(format!(
+            "upstream {} uses host_header sni without sni",
+            conf.id
+          ))
+        })?;
+        HostHeader::Fixed(header_value(sni)?)
+      }
+      HostHeaderConfig::Fixed(host) => HostHeader::Fixed(header_value(&host)?),
+    };
+
     upstreams.insert(
       conf.id,
       Arc::new(
         Upstream::new(
-          conf.addrs,
+          conf
+            .addrs
+            .iter()
+            .map(|addr| {
+              let state = if conf.disabled.contains(addr) {
+                MemberState::Disabled
+              } else {
+                MemberState::Active
+              };
+              (*addr, conf.weights.get(addr).copied().unwrap_or(1), state)
+            })
+            .collect(),
           conf.sni.map(|name| ServerName::try_from(&*name).unwrap()),
+          host_header,
+          conf.concurrency.as_ref().map(build_limiter).transpose()?,
         )
         .await,
       ),
     );
   }
 
-  let mut services: HashMap<String, Arc<dyn Service + Send + Sync>> =
-    HashMap::with_capacity(config.services.proxy.len());
+  let mut services: HashMap<String, Arc<dyn Service + Send + Sync>> = HashMap::with_capacity(
+    config.services.proxy.len() + config.services.redirect.len() + config.services.respond.len(),
+  );
+
+  let mut service_infos = Vec::with_capacity(services.capacity());
 
   for config in config.services.proxy {
+    service_infos.push(ServiceInfo {
+      id: config.id.clone(),
+      kind: "proxy",
+      target: config.upstream.clone(),
+    });
+    services.insert(
+      config.id,
+      Arc::new(ProxyService::new(lookup(
+        &upstreams,
+        "upstream",
+        &config.upstream,
+      )?)),
+    );
+  }
+
+  for config in config.services.redirect {
+    let code = StatusCode::from_u16(config.code)
+      .ok()
+      .filter(|code| matches!(code.as_u16(), 301 | 302 | 307 | 308))
+      .ok_or_else(|| {
+        PuxError::Config(format!(
+          "redirect code of {} must be one of 301, 302, 307 or 308",
+          config.id
+        ))
+      })?;
+
+    service_infos.push(ServiceInfo {
+      id: config.id.clone(),
+      kind: "redirect",
+      target: config.location.clone(),
+    });
     services.insert(
       config.id,
-      Arc::new(ProxyService::new(
-        upstreams.get(&config.upstream).unwrap().clone(),
+      Arc::new(RedirectService::new(
+        &config.location,
+        code,
+        config.preserve_path,
       )),
     );
   }
 
+  for config in config.services.respond {
+    service_infos.push(ServiceInfo {
+      id: config.id.clone(),
+      kind: "respond",
+      target: config.status.to_string(),
+    });
+    services.insert(config.id.clone(), Arc::new(build_respond_service(config)?));
+  }
+
+  let mut maintenances = HashMap::with_capacity(config.maintenance.len());
+  for conf in config.maintenance {
+    let maintenance = Arc::new(build_maintenance(&conf)?);
+    if let Some(flag) = conf.flag {
+      maintenance.watch(PathBuf::from(flag));
+    }
+
+    maintenances.insert(conf.id, maintenance);
+  }
+
+  let mut error_pages = HashMap::with_capacity(config.error_pages.len());
+  for conf in config.error_pages {
+    error_pages.insert(conf.id.clone(), Arc::new(build_error_pages(conf)?));
+  }
+
+  let mut middlewares: HashMap<String, Arc<dyn Middleware + Send + Sync>> = HashMap::new();
+
+  for conf in config.middlewares.basic_auth {
+    let mut users = HashMap::new();
+    for entry in &conf.users {
+      users.extend(parse_htpasswd(entry));
+    }
+    if let Some(htpasswd) = &conf.htpasswd {
+      let content = std::fs::read_to_string(htpasswd).map_err(|err| {
+        PuxError::Config(format!("Unable to read htpasswd {}: {}", htpasswd, err))
+      })?;
+      users.extend(parse_htpasswd(&content));
+    }
+
+    let user_header = conf.user_header.as_deref().map(header_name).transpose()?;
+
+    middlewares.insert(
+      conf.id,
+      Arc::new(BasicAuth::new(
+        conf.realm,
+        users,
+        conf.strip_header,
+        user_header,
+      )),
+    );
+  }
+
+  for conf in config.middlewares.forward_auth {
+    middlewares.insert(
+      conf.id,
+      Arc::new(ForwardAuth::new(
+        lookup(&upstreams, "upstream", &conf.upstream)?,
+        conf.path,
+        header_names(&conf.request_headers)?,
+        header_names(&conf.response_headers)?,
+      )),
+    );
+  }
+
+  for conf in config.middlewares.jwt {
+    let jwt = Arc::new(Jwt::new(&conf)?);
+
+    if let Some(path) = conf.jwks_file {
+      jwt.load_jwks(JwksSource::File(PathBuf::from(path)))?;
+    }
+    if let Some(upstream) = conf.jwks_upstream {
+      let uri = conf.jwks_uri.as_deref().ok_or_else(|| {
+        PuxError::Config(format!(
+          "jwt {} requires a jwks_uri with jwks_upstream",
+          conf.id
+        ))
+      })?;
+      jwt.load_jwks(JwksSource::Upstream(
+        lookup(&upstreams, "upstream", &upstream)?,
+        Uri::try_from(uri)
+          .map_err(|err| PuxError::Config(format!("invalid jwks_uri {}: {}", uri, err)))?,
+        Duration::from_secs(conf.jwks_refresh),
+      ))?;
+    }
+
+    middlewares.insert(conf.id, jwt);
+  }
+
+  for conf in config.middlewares.rate_limit {
+    let key = match conf.key {
+      RateLimitKeyConfig::Ip => RateLimitKey::Ip(conf.ipv4_prefix, conf.ipv6_prefix),
+      RateLimitKeyConfig::Header => {
+        let header = conf.header.as_deref().ok_or_else(|| {
+          PuxError::Config(format!(
+            "rate limit {} requires a header for the header key",
+            conf.id
+          ))
+        })?;
+        RateLimitKey::Header(header_name(header)?)
+      }
+      RateLimitKeyConfig::User => RateLimitKey::User,
+    };
+    let burst = conf.burst.unwrap_or(conf.rate);
+
+    middlewares.insert(
+      conf.id,
+      RateLimit::new(key, conf.rate / conf.period, burst)?,
+    );
+  }
+
+  for conf in config.middlewares.concurrency_limit {
+    middlewares.insert(
+      conf.id,
+      Arc::new(ConcurrencyLimit::new(build_limiter(&conf.concurrency)?)),
+    );
+  }
+
+  for conf in config.middlewares.ip_filter {
+    let list = |nets: Vec<String>, files: Vec<String>| {
+      NetList::new(
+        parse_nets(&nets)?,
+        files.into_iter().map(PathBuf::from).collect(),
+      )
+    };
+
+    middlewares.insert(
+      conf.id,
+      IpFilter::new(
+        list(conf.allow, conf.allow_files)?,
+        list(conf.deny, conf.deny_files)?,
+        parse_nets(&conf.trusted_proxies)?,
+      ),
+    );
+  }
+
+  for conf in config.middlewares.compression {
+    let encodings = conf
+      .encodings
+      .iter()
+      .map(|encoding| match encoding {
+        EncodingConfig::Zstd => Encoding::Zstd,
+        EncodingConfig::Brotli => Encoding::Brotli,
+        EncodingConfig::Gzip => Encoding::Gzip,
+      })
+      .collect();
+
+    middlewares.insert(
+      conf.id,
+      Arc::new(Compression::new(
+        encodings,
+        conf.min_size,
+        conf.mime_types,
+        conf.level,
+      )),
+    );
+  }
+
+  for conf in config.middlewares.headers {
+    middlewares.insert(
+      conf.id,
+      Arc::new(Headers::new(
+        build_header_rules(conf.request)?,
+        build_header_rules(conf.response)?,
+      )),
+    );
+  }
+
+  for conf in config.middlewares.security_headers {
+    let hsts = conf
+      .hsts
+      .map(|hsts| {
+        let mut value = format!("max-age={}", hsts.max_age);
+        if hsts.include_subdomains {
+          value.push_str("; includeSubDomains");
+        }
+        if hsts.preload {
+          value.push_str("; preload");
+        }
+        header_value(&value)
+      })
+      .transpose()?;
+
+    let headers = [
+      (
+        X_CONTENT_TYPE_OPTIONS,
+        conf.content_type_options.then(|| "nosniff".to_string()),
+      ),
+      (X_FRAME_OPTIONS, conf.frame_options),
+      (REFERRER_POLICY, conf.referrer_policy),
+      (
+        HeaderName::from_static("permissions-policy"),
+        conf.permissions_policy,
+      ),
+      (CONTENT_SECURITY_POLICY, conf.content_security_policy),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some(header_value(&value?).map(|value| (name, value))))
+    .collect::<PuxResult<_>>()?;
+
+    middlewares.insert(
+      conf.id,
+      Arc::new(SecurityHeaders::new(hsts, headers, conf.override_existing)),
+    );
+  }
+
+  for conf in config.middlewares.cors {
+    let origins = conf
+      .origins
+      .iter()
+      .map(|raw| Ok(AllowedOrigin::parse(raw)))
+      .chain(
+        conf
+          .origin_regex
+          .iter()
+          .map(|raw| AllowedOrigin::regex(raw)),
+      )
+      .collect::<PuxResult<_>>()?;
+    let methods = conf
+      .methods
+      .iter()
+      .map(|raw| method(raw))
+      .collect::<PuxResult<_>>()?;
+
+    middlewares.insert(
+      conf.id,
+      Arc::new(Cors::new(
+        origins,
+        methods,
+        header_names(&conf.headers)?,
+        header_names(&conf.expose_headers)?,
+        conf.credentials,
+        conf.max_age,
+      )?),
+    );
+  }
+
   let mut entrypoints = Vec::with_capacity(config.entrypoints.len());
-  for cfg in config.entrypoints {
-    let mut routes = Routes::new();
+  let mut access_logs = Vec::new();
+  let mut entrypoint_infos = Vec::with_capacity(config.entrypoints.len());
+  for cfg in &config.entrypoints {
+    let mut routes = RoutesBuilder::new();
     for route in &config.routes {
       if route.entrypoints.contains(&cfg.id) {
         routes.insert(
           route.host.to_string(),
-          route.path.clone(),
-          services.get(&route.service).unwrap().clone(),
+          route.priority,
+          build_path(&route.path)?,
+          Route {
+            name: route_name(route),
+            matcher: route.matcher.as_ref().map(build_matcher).transpose()?,
+            rewrite: build_rewrite(route)?,
+            maintenance: route
+              .maintenance
+              .as_ref()
+              .map(|id| lookup(&maintenances, "maintenance", id))
+              .transpose()?,
+            error_pages: route
+              .error_pages
+              .as_ref()
+              .map(|id| lookup(&error_pages, "error pages", id))
+              .transpose()?,
+            intercept_errors: route.intercept_errors,
+            middlewares: cfg
+              .middlewares
+              .iter()
+              .chain(&route.middlewares)
+              .map(|id| lookup(&middlewares, "middleware", id))
+              .collect::<PuxResult<_>>()?,
+            service: lookup(&services, "service", &route.service)?,
+            service_id: route.service.clone(),
+            middleware_ids: cfg
+              .middlewares
+              .iter()
+              .chain(&route.middlewares)
+              .cloned()
+              .collect(),
+          },
         );
       }
     }
 
-    let handler = Arc::new(Handler::new(routes));
+    let https_redirect = cfg.redirect_to.as_ref().map(|target| -> PuxResult<_> {
+      let target = config
+        .entrypoints
+        .iter()
+        .find(|entrypoint| &entrypoint.id == target && entrypoint.tls)
+        .ok_or_else(|| {
+          PuxError::Config(format!(
+            "redirect_to of {} has to reference a tls entrypoint",
+            cfg.id
+          ))
+        })?;
+
+      let location = match target.addr.port() {
+        443 => "https://{host}{path}{query}".to_string(),
+        port => format!("https://{{host}}:{}{{path}}{{query}}", port),
+      };
+
+      Ok(RedirectService::new(
+        &location,
+        StatusCode::PERMANENT_REDIRECT,
+        true,
+      ))
+    });
+    let https_redirect = https_redirect.transpose()?;
+
+    let access_log = match &cfg.access_log {
+      Some(conf) => {
+        let format = match conf.format {
+          AccessLogFormatConfig::Common => Format::Common,
+          AccessLogFormatConfig::Combined => Format::Combined,
+          AccessLogFormatConfig::Json => Format::Json,
+          AccessLogFormatConfig::Template => {
+            let template = conf.template.clone().ok_or_else(|| {
+              PuxError::Config(format!("access log of {} requires a template", cfg.id))
+            })?;
+            Format::Template(template)
+          }
+        };
+        Some(Arc::new(AccessLog::new(
+          format,
+          conf.path.clone(),
+          conf.sample_success,
+        )?))
+      }
+      None => None,
+    };
+    if let Some(access_log) = access_log.as_ref().filter(|log| log.is_file()) {
+      access_logs.push(access_log.clone());
+    }
+
+    let handler = Arc::new(Handler::new(
+      cfg.id.clone(),
+      routes.build(),
+      https_redirect,
+      cfg
+        .error_pages
+        .as_ref()
+        .map(|id| lookup(&error_pages, "error pages", id))
+        .transpose()?,
+      parse_nets(&cfg.trusted_request_ids)?,
+      access_log.clone(),
+    ));
 
     let tls_config = if cfg.tls {
       let mut config = ServerConfig::builder()
@@ -100,8 +546,16 @@ async fn main() -> PuxResult<()> {
       None
     };
 
-    match Entrypoint::bind(&cfg, handler, tls_config).await {
+    match Entrypoint::bind(cfg, handler.clone(), tls_config).await {
       Ok(entrypoint) => {
+        entrypoint_infos.push(EntrypointInfo {
+          id: cfg.id.clone(),
+          addr: cfg.addr,
+          tls: cfg.tls,
+          redirect_to: cfg.redirect_to.clone(),
+          middlewares: cfg.middlewares.clone(),
+          handler,
+        });
         entrypoints.push(entrypoint);
         info!("Entrypoint {} bound to {}", cfg.id, cfg.addr,);
       }
@@ -114,6 +568,44 @@ async fn main() -> PuxResult<()> {
     };
   }
 
+  if !access_logs.is_empty() {
+    reopen_on_signal(access_logs);
+  }
+
+  if let Some(conf) = config.metrics {
+    let exporter = Arc::new(Exporter::new(
+      conf.path,
+      upstreams
+        .iter()
+        .map(|(id, upstream)| (id.clone(), upstream.clone()))
+        .collect(),
+      cert_store.clone(),
+    ));
+    tokio::spawn(exporter.serve(conf.addr));
+    info!("Metrics entrypoint bound to {}", conf.addr);
+  }
+
+  if let Some(conf) = config.admin {
+    let admin = Arc::new(Admin::new(
+      conf.token,
+      ConfigInfo {
+        path: config_path,
+        generation: AtomicU64::new(1),
+        loaded_at,
+      },
+      entrypoint_infos,
+      service_infos,
+      upstreams
+        .iter()
+        .map(|(id, upstream)| (id.clone(), upstream.clone()))
+        .collect(),
+      cert_store.clone(),
+      logging,
+    ));
+    tokio::spawn(admin.serve(conf.addr));
+    info!("Admin entrypoint bound to {}", conf.addr);
+  }
+
   let pux = Pux::new(entrypoints);
 
   select! {
@@ -131,6 +623,8 @@ async fn main() -> PuxResult<()> {
     }
   }
 
+  telemetry::shutdown();
+
   Ok(())
 }
 
@@ -155,6 +649,277 @@ fn build_cert_store(certs: Vec<CertificateConfig>) -> CertStore {
   store
 }
 
+fn build_path(config: &PathConfig) -> PuxResult<PathPattern> {
+  Ok(match config {
+    PathConfig::Prefix(pattern) => PathPattern::parse(pattern, false),
+    PathConfig::Segments(segments) => PathPattern::parse(&segments_path(segments), false),
+    PathConfig::Exact { exact } => PathPattern::parse(exact, true),
+    PathConfig::Regex { regex } => PathPattern::Regex(build_regex(regex)?),
+  })
+}
+
+/// Joins the segments of the list form into a path with a leading slash.
+fn segments_path(segments: &[String]) -> String {
+  let path = segments.join("/");
+  match path.starts_with('/') {
+    true => path,
+    false => format!("/{}", path),
+  }
+}
+
+fn route_name(route: &RouteConfig) -> String {
+  match &route.id {
+    Some(id) => id.clone(),
+    None => match &route.path {
+      PathConfig::Prefix(pattern) => format!("{}{}", route.host, pattern),
+      PathConfig::Segments(segments) => format!("{}{}", route.host, segments_path(segments)),
+      PathConfig::Exact { exact } => format!("{}{}", route.host, exact),
+      PathConfig::Regex { regex } => format!("{}~{}", route.host, regex),
+    },
+  }
+}
+
+fn build_rewrite(route: &RouteConfig) -> PuxResult<Option<Rewrite>> {
+  if route.strip_prefix.is_none() && route.add_prefix.is_none() && route.rewrite.is_none() {
+    return Ok(None);
+  }
+
+  let regex = route
+    .rewrite
+    .as_ref()
+    .map(|rewrite| PuxResult::Ok((build_regex(&rewrite.regex)?, rewrite.replacement.clone())))
+    .transpose()?;
+
+  Ok(Some(Rewrite::new(
+    route.strip_prefix.clone(),
+    route.add_prefix.clone(),
+    regex,
+  )))
+}
+
+fn build_respond_service(config: RespondServiceConfig) -> PuxResult<RespondService> {
+  let status = StatusCode::from_u16(config.status)
+    .map_err(|_| PuxError::Config(format!("invalid status {} of {}", config.status, config.id)))?;
+
+  let mut headers = HeaderMap::with_capacity(config.headers.len());
+  for (name, value) in &config.headers {
+    headers.insert(header_name(name)?, header_value(value)?);
+  }
+
+  let body = match (config.body, config.body_file) {
+    (Some(body), None) => body.into_bytes(),
+    (None, Some(file)) => read_file(&file)?,
+    (None, None) => Vec::new(),
+    (Some(_), Some(_)) => {
+      return Err(PuxError::Config(format!(
+        "only one of body and body_file can be configured for {}",
+        config.id
+      )))
+    }
+  };
+
+  Ok(RespondService::new(status, headers, body.into()))
+}
+
+/// Answers with a 503 and the page while enabled.
+fn build_maintenance(config: &MaintenanceConfig) -> PuxResult<Maintenance> {
+  let mut headers = HeaderMap::new();
+  headers.insert(
+    CONTENT_TYPE,
+    HeaderValue::from_static(TEXT_HTML_UTF_8.as_ref()),
+  );
+  if let Some(retry_after) = config.retry_after {
+    headers.insert(RETRY_AFTER, HeaderValue::from(retry_after));
+  }
+
+  let page = read_file(&config.page)?;
+  let service = RespondService::new(StatusCode::SERVICE_UNAVAILABLE, headers, page.into());
+
+  Ok(Maintenance::new(config.id.clone(), config.enabled, service))
+}
+
+fn build_error_pages(config: ErrorPagesConfig) -> PuxResult<ErrorPages> {
+  let mut pages = ErrorPages::new();
+
+  for page in config.pages {
+    let codes = match page.status {
+      StatusRangeConfig::Code(code) => code..=code,
+      StatusRangeConfig::Range(range) => parse_status_range(&range).ok_or_else(|| {
+        PuxError::Config(format!(
+          "invalid status range {} in error pages {}",
+          range, config.id
+        ))
+      })?,
+    };
+
+    let template = String::from_utf8(read_file(&page.file)?)
+      .map_err(|_| PuxError::Config(format!("error page {} is not valid utf-8", page.file)))?;
+    pages.insert(codes, template);
+  }
+
+  Ok(pages)
+}
+
+fn build_header_rules(conf: HeaderRulesConfig) -> PuxResult<HeaderRules> {
+  let pairs = |pairs: HashMap<String, String>| {
+    pairs
+      .into_iter()
+      .map(|(key, value)| Ok((header_name(&key)?, value)))
+      .collect::<PuxResult<_>>()
+  };
+
+  Ok(HeaderRules {
+    set: pairs(conf.set)?,
+    append: pairs(conf.append)?,
+    remove: header_names(&conf.remove)?,
+  })
+}
+
+fn build_limiter(conf: &ConcurrencyConfig) -> PuxResult<Arc<Limiter>> {
+  if conf.limit == 0 {
+    return Err(PuxError::Config(
+      "concurrency limit must be at least 1".to_string(),
+    ));
+  }
+  if conf.queue > 0 && conf.queue_timeout == 0 {
+    return Err(PuxError::Config(
+      "concurrency queue requires a queue_timeout".to_string(),
+    ));
+  }
+  if let Some(adaptive) = &conf.adaptive {
+    if adaptive.min_limit == 0 || adaptive.min_limit > adaptive.max_limit {
+      return Err(PuxError::Config(format!(
+        "invalid adaptive concurrency limits {}..{}",
+        adaptive.min_limit, adaptive.max_limit
+      )));
+    }
+  }
+
+  Ok(Arc::new(Limiter::new(
+    conf.limit,
+    conf.queue,
+    Duration::from_millis(conf.queue_timeout),
+    conf.adaptive.as_ref().map(|adaptive| Adaptive {
+      min_limit: adaptive.min_limit,
+      max_limit: adaptive.max_limit,
+      latency: Duration::from_millis(adaptive.latency),
+    }),
+  )))
+}
+
+fn build_matcher(config: &MatchConfig) -> PuxResult<Matcher> {
+  let mut matchers = Vec::new();
+
+  if !config.method.is_empty() {
+    let methods = config
+      .method
+      .iter()
+      .map(|raw| method(raw))
+      .collect::<PuxResult<_>>()?;
+    matchers.push(Matcher::Method(methods));
+  }
+
+  for header in &config.headers {
+    matchers.push(Matcher::Header(
+      header_name(&header.name)?,
+      build_value_matcher(header)?,
+    ));
+  }
+
+  for query in &config.query {
+    matchers.push(Matcher::Query(
+      query.name.clone(),
+      build_value_matcher(query)?,
+    ));
+  }
+
+  if !config.client_ip.is_empty() {
+    matchers.push(Matcher::ClientIp(parse_nets(&config.client_ip)?));
+  }
+
+  if !config.sni.is_empty() {
+    matchers.push(Matcher::Sni(config.sni.clone()));
+  }
+
+  if !config.all.is_empty() {
+    matchers.push(Matcher::All(
+      config
+        .all
+        .iter()
+        .map(build_matcher)
+        .collect::<PuxResult<_>>()?,
+    ));
+  }
+
+  if !config.any.is_empty() {
+    matchers.push(Matcher::Any(
+      config
+        .any
+        .iter()
+        .map(build_matcher)
+        .collect::<PuxResult<_>>()?,
+    ));
+  }
+
+  Ok(match matchers.len() {
+    1 => matchers.remove(0),
+    _ => Matcher::All(matchers),
+  })
+}
+
+fn build_value_matcher(config: &ValueMatchConfig) -> PuxResult<ValueMatcher> {
+  match (&config.value, &config.regex) {
+    (None, None) => Ok(ValueMatcher::Present),
+    (Some(value), None) => Ok(ValueMatcher::Exact(value.clone())),
+    (None, Some(regex)) => Ok(ValueMatcher::Regex(build_regex(regex)?)),
+    (Some(_), Some(_)) => Err(PuxError::Config(format!(
+      "only one of value and regex can be configured for {}",
+      config.name
+    ))),
+  }
+}
+
+/// Accepts networks in cidr notation as well as single addresses.
+fn parse_nets(raw: &[String]) -> PuxResult<Vec<IpNet>> {
+  raw
+    .iter()
+    .map(|raw| parse_net(raw).ok_or_else(|| PuxError::Config(format!("invalid network {}", raw))))
+    .collect()
+}
+
+fn build_regex(raw: &str) -> PuxResult<Regex> {
+  Regex::new(raw).map_err(|err| PuxError::Config(format!("invalid regex {}: {}", raw, err)))
+}
+
+fn method(raw: &str) -> PuxResult<Method> {
+  Method::from_bytes(raw.to_ascii_uppercase().as_bytes())
+    .map_err(|_| PuxError::Config(format!("invalid method {}", raw)))
+}
+
+fn header_name(raw: &str) -> PuxResult<HeaderName> {
+  HeaderName::try_from(raw).map_err(|_| PuxError::Config(format!("invalid header name {}", raw)))
+}
+
+fn header_names(raw: &[String]) -> PuxResult<Vec<HeaderName>> {
+  raw.iter().map(|raw| header_name(raw)).collect()
+}
+
+fn header_value(raw: &str) -> PuxResult<HeaderValue> {
+  HeaderValue::try_from(raw).map_err(|_| PuxError::Config(format!("invalid header value {}", raw)))
+}
+
+/// Clones the configured item with the id, references to unknown ids are configuration errors.
+fn lookup<T: Clone>(items: &HashMap<String, T>, kind: &str, id: &str) -> PuxResult<T> {
+  items
+    .get(id)
+    .cloned()
+    .ok_or_else(|| PuxError::Config(format!("unknown {} {}", kind, id)))
+}
+
+fn read_file(path: &str) -> PuxResult<Vec<u8>> {
+  std::fs::read(path).map_err(|err| PuxError::Config(format!("Unable to read {}: {}", path, err)))
+}
+
 async fn shutdown_signal() {
   let ctrl_c = async { ctrl_c().await.expect("failed to install Ctrl+C handler") };
 
@@ -176,3 +941,94 @@ async fn shutdown_signal() {
   #[cfg(not(unix))]
   ctrl_c.await;
 }
+
+#[cfg(test)]
+mod tests {
+  use hyper::{Body, Request, Response};
+
+  use crate::middleware::testing::route;
+  use crate::service::Service;
+
+  use super::*;
+
+  fn file(name: &str, content: &str) -> String {
+    let path = std::env::temp_dir().join(format!("pux-{}-{}", std::process::id(), name));
+    std::fs::write(&path, content).unwrap();
+    path.display().to_string()
+  }
+
+  async fn respond(service: &RespondService) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let resp = service.handle(Request::new(Body::empty())).await.unwrap();
+    let (parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    (parts.status, parts.headers, body.to_vec())
+  }
+
+  #[tokio::test]
+  async fn responds_with_inline_body() {
+    let config = serde_yaml::from_str(
+      "{ id: teapot, status: 418, headers: { content-type: text/plain }, body: short and stout }",
+    )
+    .unwrap();
+    let (status, headers, body) = respond(&build_respond_service(config).unwrap()).await;
+    assert_eq!(status, StatusCode::IM_A_TEAPOT);
+    assert_eq!(headers[CONTENT_TYPE], "text/plain");
+    assert_eq!(body, b"short and stout");
+  }
+
+  #[tokio::test]
+  async fn responds_with_body_file() {
+    let path = file("respond-body", "from a file");
+    let config = serde_yaml::from_str(&format!("{{ id: file, body_file: '{}' }}", path)).unwrap();
+    let (status, _, body) = respond(&build_respond_service(config).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"from a file");
+  }
+
+  #[test]
+  fn rejects_invalid_respond_services() {
+    let build = |raw: &str| build_respond_service(serde_yaml::from_str(raw).unwrap()).is_err();
+    assert!(build("{ id: both, body: inline, body_file: /dev/null }"));
+    assert!(build("{ id: status, status: 1000 }"));
+    assert!(build("{ id: missing, body_file: /nonexistent/pux-body }"));
+  }
+
+  #[tokio::test]
+  async fn maintenance_answers_with_retry_after_and_page() {
+    let page = file("maintenance-page", "<h1>back soon</h1>");
+    let config: MaintenanceConfig = serde_yaml::from_str(&format!(
+      "{{ id: migration, retry_after: 120, page: '{}' }}",
+      page
+    ))
+    .unwrap();
+    let maintenance = Arc::new(build_maintenance(&config).unwrap());
+
+    let mut route = route(|_| Ok(Response::new(Body::from("upstream"))));
+    route.maintenance = Some(maintenance.clone());
+
+    // disabled unless configured, requests reach the service
+    let resp = route.handle(Request::new(Body::empty())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    maintenance.set_enabled(true);
+    let resp = route.handle(Request::new(Body::empty())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()[RETRY_AFTER], "120");
+    assert_eq!(resp.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "<h1>back soon</h1>");
+
+    maintenance.set_enabled(false);
+    let resp = route.handle(Request::new(Body::empty())).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn maintenance_without_retry_after() {
+    let page = file("maintenance-plain", "down");
+    let config: MaintenanceConfig =
+      serde_yaml::from_str(&format!("{{ id: plain, enabled: true, page: '{}' }}", page)).unwrap();
+    let maintenance = build_maintenance(&config).unwrap();
+    assert!(maintenance.is_enabled());
+  }
+}
@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Request, Response};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::service::respond::RespondService;
+use crate::service::Service;
+use crate::PuxResult;
+
+/// Answers every request of its routes while enabled, toggled by the flag file or the admin api.
+pub(crate) struct Maintenance {
+  id: String,
+  enabled: AtomicBool,
+  service: RespondService,
+}
+
+impl Maintenance {
+  pub(crate) fn new(id: String, enabled: bool, service: RespondService) -> Self {
+    Self {
+      id,
+      enabled: AtomicBool::new(enabled),
+      service,
+    }
+  }
+
+  pub(crate) fn id(&self) -> &str {
+    self.id.as_str()
+  }
+
+  pub(crate) fn is_enabled(&self) -> bool {
+    self.enabled.load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn set_enabled(&self, enabled: bool) {
+    if self.enabled.swap(enabled, Ordering::Relaxed) != enabled {
+      info!(
+        "Maintenance {} {}",
+        self.id,
+        if enabled { "enabled" } else { "disabled" }
+      );
+    }
+  }
+
+  /// Toggles the maintenance whenever the flag file is created or removed. A maintenance enabled
+  /// in the configuration stays enabled without the flag. Changes through the admin api are kept
+  /// until the flag changes again.
+  pub(crate) fn watch(self: &Arc<Self>, flag: PathBuf) {
+    let maintenance = self.clone();
+    let configured = self.is_enabled();
+    tokio::spawn(async move {
+      let mut exists = None;
+      loop {
+        match tokio::fs::try_exists(&flag).await {
+          Ok(current) if exists != Some(current) => {
+            exists = Some(current);
+            maintenance.set_enabled(configured || current);
+          }
+          Ok(_) => {}
+          Err(err) => warn!(
+            "Unable to check maintenance flag {}: {}",
+            flag.display(),
+            err
+          ),
+        }
+        sleep(Duration::from_secs(2)).await;
+      }
+    });
+  }
+
+  pub(crate) async fn handle(&self, req: Request<Body>) -> PuxResult<Response<Body>> {
+    self.service.handle(req).await
+  }
+}
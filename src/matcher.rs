@@ -0,0 +1,70 @@
+use hyper::header::HeaderName;
+use hyper::{Body, Method, Request};
+use ipnet::IpNet;
+use regex::Regex;
+
+use crate::entrypoint::ConnInfo;
+
+pub(crate) enum Matcher {
+  Method(Vec<Method>),
+  Header(HeaderName, ValueMatcher),
+  Query(String, ValueMatcher),
+  ClientIp(Vec<IpNet>),
+  Sni(Vec<String>),
+  All(Vec<Matcher>),
+  Any(Vec<Matcher>),
+}
+
+pub(crate) enum ValueMatcher {
+  Present,
+  Exact(String),
+  Regex(Regex),
+}
+
+impl Matcher {
+  pub(crate) fn matches(&self, req: &Request<Body>) -> bool {
+    match self {
+      Self::Method(methods) => methods.contains(req.method()),
+      Self::Header(name, value) => req
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|raw| raw.to_str().ok())
+        .any(|raw| value.matches(raw)),
+      // values are compared in their raw (percent encoded) form
+      Self::Query(name, value) => req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .any(|(key, raw)| key == name && value.matches(raw)),
+      Self::ClientIp(nets) => match req.extensions().get::<ConnInfo>() {
+        Some(conn_info) => nets
+          .iter()
+          .any(|net| net.contains(&conn_info.peer_addr.ip())),
+        None => false,
+      },
+      Self::Sni(names) => match req
+        .extensions()
+        .get::<ConnInfo>()
+        .and_then(|conn_info| conn_info.sni.as_deref())
+      {
+        Some(sni) => names.iter().any(|name| name.eq_ignore_ascii_case(sni)),
+        None => false,
+      },
+      Self::All(matchers) => matchers.iter().all(|matcher| matcher.matches(req)),
+      Self::Any(matchers) => matchers.iter().any(|matcher| matcher.matches(req)),
+    }
+  }
+}
+
+impl ValueMatcher {
+  fn matches(&self, raw: &str) -> bool {
+    match self {
+      Self::Present => true,
+      Self::Exact(value) => value == raw,
+      Self::Regex(regex) => regex.is_match(raw),
+    }
+  }
+}
@@ -0,0 +1,236 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use once_cell::sync::Lazy;
+use prometheus::{
+  Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tracing::error;
+
+use crate::cert::CertStore;
+use crate::upstream::Upstream;
+
+/// Process wide metrics, rendered in the prometheus text format by the [`Exporter`].
+pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub(crate) struct Metrics {
+  registry: Registry,
+  /// By entrypoint, route and status class.
+  pub(crate) requests: IntCounterVec,
+  /// By entrypoint and route, until the response head is ready.
+  pub(crate) request_duration: HistogramVec,
+  /// By upstream address.
+  pub(crate) upstream_connect_errors: IntCounterVec,
+  /// By upstream address, including the connect if no idle connection was available.
+  pub(crate) upstream_duration: HistogramVec,
+  /// By entrypoint and reason.
+  pub(crate) tls_handshake_failures: IntCounterVec,
+}
+
+/// Gauges derived from the current state, collected per scrape so concurrent scrapes can not see
+/// each other's partial updates.
+struct Snapshot {
+  registry: Registry,
+  /// By upstream, address and state.
+  upstream_connections: IntGaugeVec,
+  /// By certificate name.
+  cert_expiry: IntGaugeVec,
+}
+
+/// Serves the metrics on a dedicated address.
+pub(crate) struct Exporter {
+  path: String,
+  upstreams: Vec<(String, Arc<Upstream>)>,
+  cert_store: Arc<CertStore>,
+}
+
+impl Metrics {
+  fn new() -> Self {
+    let registry = Registry::new_custom(Some("pux".to_string()), None).unwrap();
+
+    let requests = IntCounterVec::new(
+      Opts::new("requests_total", "Handled requests"),
+      &["entrypoint", "route", "status"],
+    )
+    .unwrap();
+    let request_duration = HistogramVec::new(
+      HistogramOpts::new(
+        "request_duration_seconds",
+        "Time until the response is ready",
+      ),
+      &["entrypoint", "route"],
+    )
+    .unwrap();
+    let upstream_connect_errors = IntCounterVec::new(
+      Opts::new(
+        "upstream_connect_errors_total",
+        "Failed connection attempts to upstreams",
+      ),
+      &["addr"],
+    )
+    .unwrap();
+    let upstream_duration = HistogramVec::new(
+      HistogramOpts::new(
+        "upstream_duration_seconds",
+        "Time until the upstream response head is received",
+      ),
+      &["addr"],
+    )
+    .unwrap();
+    let tls_handshake_failures = IntCounterVec::new(
+      Opts::new("tls_handshake_failures_total", "Failed tls handshakes"),
+      &["entrypoint", "reason"],
+    )
+    .unwrap();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry
+      .register(Box::new(request_duration.clone()))
+      .unwrap();
+    registry
+      .register(Box::new(upstream_connect_errors.clone()))
+      .unwrap();
+    registry
+      .register(Box::new(upstream_duration.clone()))
+      .unwrap();
+    registry
+      .register(Box::new(tls_handshake_failures.clone()))
+      .unwrap();
+
+    Self {
+      registry,
+      requests,
+      request_duration,
+      upstream_connect_errors,
+      upstream_duration,
+      tls_handshake_failures,
+    }
+  }
+}
+
+impl Snapshot {
+  fn new() -> Self {
+    let registry = Registry::new_custom(Some("pux".to_string()), None).unwrap();
+
+    let upstream_connections = IntGaugeVec::new(
+      Opts::new("upstream_connections", "Open upstream connections"),
+      &["upstream", "addr", "state"],
+    )
+    .unwrap();
+    let cert_expiry = IntGaugeVec::new(
+      Opts::new(
+        "certificate_expiry_timestamp_seconds",
+        "Expiry of the served certificates",
+      ),
+      &["name"],
+    )
+    .unwrap();
+
+    registry
+      .register(Box::new(upstream_connections.clone()))
+      .unwrap();
+    registry.register(Box::new(cert_expiry.clone())).unwrap();
+
+    Self {
+      registry,
+      upstream_connections,
+      cert_expiry,
+    }
+  }
+}
+
+impl Exporter {
+  pub(crate) fn new(
+    path: String,
+    upstreams: Vec<(String, Arc<Upstream>)>,
+    cert_store: Arc<CertStore>,
+  ) -> Self {
+    Self {
+      path,
+      upstreams,
+      cert_store,
+    }
+  }
+
+  pub(crate) async fn serve(self: Arc<Self>, addr: SocketAddr) {
+    let make_service = make_service_fn(move |_| {
+      let exporter = self.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |req| {
+          let exporter = exporter.clone();
+          async move { Ok::<_, Infallible>(exporter.handle(req).await) }
+        }))
+      }
+    });
+
+    let result = match Server::try_bind(&addr) {
+      Ok(server) => server.serve(make_service).await,
+      Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+      error!("Metrics entrypoint on {} stopped: {}", addr, err);
+    }
+  }
+
+  async fn handle(&self, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != self.path {
+      let mut resp = Response::new(Body::empty());
+      *resp.status_mut() = StatusCode::NOT_FOUND;
+      return resp;
+    }
+
+    let mut families = METRICS.registry.gather();
+    families.extend(self.snapshot().await.registry.gather());
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&families, &mut buffer) {
+      error!("Unable to encode metrics: {}", err);
+    }
+
+    let mut resp = Response::new(Body::from(buffer));
+    if let Ok(content_type) = encoder.format_type().parse() {
+      resp.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    resp
+  }
+
+  async fn snapshot(&self) -> Snapshot {
+    let snapshot = Snapshot::new();
+    let connections = &snapshot.upstream_connections;
+    for (id, upstream) in &self.upstreams {
+      for stats in upstream.pool_stats().await {
+        let addr = stats.addr.to_string();
+        connections
+          .with_label_values(&[id, &addr, "idle"])
+          .set(stats.idle as i64);
+        connections
+          .with_label_values(&[id, &addr, "active"])
+          .set(stats.active as i64);
+      }
+    }
+
+    for cert in self.cert_store.certificates() {
+      snapshot
+        .cert_expiry
+        .with_label_values(&[&cert.name])
+        .set(cert.not_after);
+    }
+    snapshot
+  }
+}
+
+/// `2xx`, `4xx`, ...
+pub(crate) fn status_class(status: StatusCode) -> &'static str {
+  match status.as_u16() / 100 {
+    1 => "1xx",
+    2 => "2xx",
+    3 => "3xx",
+    4 => "4xx",
+    _ => "5xx",
+  }
+}
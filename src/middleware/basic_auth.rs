@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderName, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::http::HeaderValue;
+use hyper::{http, Body, HeaderMap, Request, Response, StatusCode};
+use pwhash::{bcrypt, sha256_crypt, sha512_crypt};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::PuxError;
+use crate::middleware::{quoted_string, AuthenticatedUser, Middleware, Next};
+use crate::PuxResult;
+
+const CACHE_TTL: Duration = Duration::from_secs(300);
+const CACHE_SIZE: usize = 1024;
+
+pub(crate) struct BasicAuth {
+  realm: String,
+  /// User name to password hash.
+  users: HashMap<String, String>,
+  strip_header: bool,
+  user_header: Option<HeaderName>,
+  /// Verified for unknown users, so they take as long to reject as known ones.
+  dummy_hash: Option<String>,
+  /// Digests of recently verified authorization headers, so the expensive hash is not verified
+  /// on every request and the credentials are not kept in memory.
+  verified: Mutex<HashMap<[u8; 32], Instant>>,
+  /// Random per process, so the digests can not be looked up in precomputed tables.
+  salt: [u8; 16],
+}
+
+impl BasicAuth {
+  pub(crate) fn new(
+    realm: String,
+    users: HashMap<String, String>,
+    strip_header: bool,
+    user_header: Option<HeaderName>,
+  ) -> Self {
+    for (user, hash) in &users {
+      if !is_supported(hash) {
+        warn!("Unsupported password hash format for user {}", user);
+      }
+    }
+
+    let dummy_hash = users.values().find(|hash| is_supported(hash)).cloned();
+
+    Self {
+      realm,
+      users,
+      strip_header,
+      user_header,
+      dummy_hash,
+      verified: Mutex::new(HashMap::new()),
+      salt: Uuid::new_v4().into_bytes(),
+    }
+  }
+
+  async fn authenticate(&self, authorization: &str) -> Option<String> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+      return None;
+    }
+
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    let digest = self.digest(authorization);
+
+    let (known, hash) = match self.users.get(user) {
+      Some(hash) => (true, hash.clone()),
+      None => (false, self.dummy_hash.clone()?),
+    };
+
+    if known && self.is_cached(&digest) {
+      return Some(user.to_string());
+    }
+
+    let password = password.to_string();
+    let valid = tokio::task::spawn_blocking(move || verify(&password, &hash))
+      .await
+      .unwrap_or(false);
+
+    if known && valid {
+      self.cache(digest);
+      Some(user.to_string())
+    } else {
+      None
+    }
+  }
+
+  fn digest(&self, authorization: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(self.salt);
+    hasher.update(authorization.as_bytes());
+    hasher.finalize().into()
+  }
+
+  fn is_cached(&self, digest: &[u8; 32]) -> bool {
+    let verified = self.verified.lock().unwrap();
+    verified
+      .get(digest)
+      .is_some_and(|verified_at| verified_at.elapsed() < CACHE_TTL)
+  }
+
+  fn cache(&self, digest: [u8; 32]) {
+    let mut verified = self.verified.lock().unwrap();
+    if verified.len() >= CACHE_SIZE {
+      verified.retain(|_, verified_at| verified_at.elapsed() < CACHE_TTL);
+      if verified.len() >= CACHE_SIZE {
+        verified.clear();
+      }
+    }
+    verified.insert(digest, Instant::now());
+  }
+
+  fn challenge(&self) -> PuxResult<PuxError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      WWW_AUTHENTICATE,
+      HeaderValue::try_from(format!(
+        "Basic realm={}, charset=\"UTF-8\"",
+        quoted_string(&self.realm)
+      ))
+      .map_err(http::Error::from)?,
+    );
+    Ok(PuxError::StatusWithHeaders(
+      StatusCode::UNAUTHORIZED,
+      headers,
+    ))
+  }
+}
+
+#[async_trait]
+impl Middleware for BasicAuth {
+  async fn handle(&self, mut req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let authorization = req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|raw| raw.to_str().ok());
+
+    let user = match authorization {
+      Some(authorization) => self.authenticate(authorization).await,
+      None => None,
+    };
+
+    let user = match user {
+      Some(user) => user,
+      None => return Err(self.challenge()?),
+    };
+
+    if self.strip_header {
+      req.headers_mut().remove(AUTHORIZATION);
+    }
+
+    if let Some(name) = &self.user_header {
+      let value = HeaderValue::try_from(user.as_str()).map_err(http::Error::from)?;
+      req.headers_mut().insert(name.clone(), value);
+    }
+    req.extensions_mut().insert(AuthenticatedUser(user));
+
+    next.run(req).await
+  }
+}
+
+/// Parses the `user:hash` lines of a htpasswd file.
+pub(crate) fn parse_htpasswd(content: &str) -> impl Iterator<Item = (String, String)> + '_ {
+  content
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .filter_map(|line| line.split_once(':'))
+    .map(|(user, hash)| (user.to_string(), hash.to_string()))
+}
+
+fn is_supported(hash: &str) -> bool {
+  ["$2a$", "$2b$", "$2y$", "$5$", "$6$", "$argon2"]
+    .iter()
+    .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+  if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+    bcrypt::verify(password, hash)
+  } else if hash.starts_with("$5$") {
+    sha256_crypt::verify(password, hash)
+  } else if hash.starts_with("$6$") {
+    sha512_crypt::verify(password, hash)
+  } else if hash.starts_with("$argon2") {
+    PasswordHash::new(hash)
+      .map(|hash| {
+        Argon2::default()
+          .verify_password(password.as_bytes(), &hash)
+          .is_ok()
+      })
+      .unwrap_or(false)
+  } else {
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::testing::{route, run};
+
+  const USER_HEADER: HeaderName = HeaderName::from_static("x-user");
+
+  fn basic_auth(realm: &str) -> BasicAuth {
+    let users = parse_htpasswd(&format!(
+      "# users\nalice:{}\n\nbob:plaintext\n",
+      sha512_crypt::hash("secret").unwrap()
+    ))
+    .collect();
+    BasicAuth::new(realm.to_string(), users, true, Some(USER_HEADER))
+  }
+
+  fn credentials(user: &str, password: &str) -> String {
+    format!(
+      "Basic {}",
+      STANDARD.encode(format!("{}:{}", user, password))
+    )
+  }
+
+  fn request(authorization: &str) -> Request<Body> {
+    Request::builder()
+      .header(AUTHORIZATION, authorization)
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn accepts_valid_credentials() {
+    let auth = basic_auth("pux");
+    let route = route(|req| {
+      assert!(!req.headers().contains_key(AUTHORIZATION));
+      assert_eq!(req.headers()[USER_HEADER], "alice");
+      let user = req.extensions().get::<AuthenticatedUser>().unwrap();
+      assert_eq!(user.0, "alice");
+      Ok(Response::new(Body::empty()))
+    });
+
+    let authorization = credentials("alice", "secret");
+    assert!(run(&auth, &route, request(&authorization)).await.is_ok());
+    assert!(auth.is_cached(&auth.digest(&authorization)));
+    // served from the cache
+    assert!(run(&auth, &route, request(&authorization)).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_credentials() {
+    let auth = basic_auth("pux");
+    assert_eq!(
+      auth.authenticate(&credentials("alice", "wrong")).await,
+      None
+    );
+    // unsupported hashes are never valid
+    assert_eq!(
+      auth.authenticate(&credentials("bob", "plaintext")).await,
+      None
+    );
+    assert!(auth.verified.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn rejects_unknown_users_with_dummy_hash() {
+    let auth = basic_auth("pux");
+    assert!(auth.dummy_hash.as_deref().is_some_and(is_supported));
+    assert_eq!(
+      auth.authenticate(&credentials("mallory", "secret")).await,
+      None
+    );
+
+    let without_hashes = BasicAuth::new("pux".to_string(), HashMap::new(), false, None);
+    assert_eq!(
+      without_hashes
+        .authenticate(&credentials("mallory", "secret"))
+        .await,
+      None
+    );
+  }
+
+  #[tokio::test]
+  async fn rejects_malformed_authorization() {
+    let auth = basic_auth("pux");
+    for authorization in [
+      "Basic",
+      "Bearer token",
+      "Basic !!!",
+      &format!("Basic {}", STANDARD.encode("alice")),
+      &format!("Basic {}", STANDARD.encode([0xff, b':', b'x'])),
+    ] {
+      assert_eq!(auth.authenticate(authorization).await, None);
+    }
+    assert!(auth
+      .authenticate(&credentials("alice", "secret").replace("Basic", "basic"))
+      .await
+      .is_some());
+  }
+
+  #[tokio::test]
+  async fn challenges_with_quoted_realm() {
+    let auth = basic_auth("a \"b\" \\c");
+    let route = route(|_| Ok(Response::new(Body::empty())));
+
+    match run(&auth, &route, Request::new(Body::empty())).await {
+      Err(PuxError::StatusWithHeaders(code, headers)) => {
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+          headers[WWW_AUTHENTICATE],
+          "Basic realm=\"a \\\"b\\\" \\\\c\", charset=\"UTF-8\""
+        );
+      }
+      _ => panic!("expected a challenge"),
+    }
+  }
+}
@@ -0,0 +1,350 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use hyper::header::{
+  ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+  ETAG, VARY,
+};
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::middleware::{Middleware, Next};
+use crate::PuxResult;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Encoding {
+  Zstd,
+  Brotli,
+  Gzip,
+}
+
+/// Only compressed if listed explicitly, not by a prefix like `text/`.
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Compresses responses on the fly with the best encoding accepted by the client.
+pub(crate) struct Compression {
+  /// Ordered by preference, used when the client accepts multiple with the same quality.
+  encodings: Vec<Encoding>,
+  min_size: u64,
+  /// Exact types or prefixes ending with `/`, like `text/`, prefixes exclude `text/event-stream`.
+  mime_types: Vec<String>,
+  level: Option<i32>,
+}
+
+impl Encoding {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Zstd => "zstd",
+      Self::Brotli => "br",
+      Self::Gzip => "gzip",
+    }
+  }
+}
+
+impl Compression {
+  pub(crate) fn new(
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    mime_types: Vec<String>,
+    level: Option<i32>,
+  ) -> Self {
+    Self {
+      encodings,
+      min_size,
+      mime_types,
+      level,
+    }
+  }
+
+  /// Picks the configured encoding with the highest quality from the accept-encoding header.
+  fn negotiate(&self, accept: Option<&HeaderValue>) -> Option<Encoding> {
+    let accept = accept.and_then(|accept| accept.to_str().ok())?;
+
+    let mut qualities = vec![None; self.encodings.len()];
+    let mut wildcard = None;
+
+    for coding in accept.split(',') {
+      let mut params = coding.split(';');
+      let name = params
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+      let quality = params
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|quality| quality.parse::<f32>().ok())
+        .unwrap_or(1.0);
+
+      if name == "*" {
+        wildcard = Some(quality);
+      } else if let Some(i) = self.encodings.iter().position(|e| e.name() == name) {
+        qualities[i] = Some(quality);
+      }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for (encoding, quality) in self.encodings.iter().zip(qualities) {
+      let quality = quality.or(wildcard).unwrap_or(0.0);
+      if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+        best = Some((*encoding, quality));
+      }
+    }
+
+    best.map(|(encoding, _)| encoding)
+  }
+
+  fn is_compressible(&self, resp: &Response<Body>) -> bool {
+    let mime = match resp
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|raw| raw.to_str().ok())
+    {
+      Some(raw) => raw.split(';').next().unwrap_or_default().trim(),
+      None => return false,
+    };
+
+    self.mime_types.iter().any(|allowed| {
+      if allowed.ends_with('/') {
+        // the encoders buffer, events would only be sent once enough of them piled up
+        let prefix = mime.as_bytes().get(..allowed.len());
+        prefix.is_some_and(|prefix| prefix.eq_ignore_ascii_case(allowed.as_bytes()))
+          && !mime.eq_ignore_ascii_case(EVENT_STREAM)
+      } else {
+        mime.eq_ignore_ascii_case(allowed)
+      }
+    })
+  }
+
+  fn level(&self) -> Level {
+    match self.level {
+      Some(level) => Level::Precise(level),
+      None => Level::Default,
+    }
+  }
+}
+
+#[async_trait]
+impl Middleware for Compression {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let encoding = self.negotiate(req.headers().get(ACCEPT_ENCODING));
+    let is_head = req.method() == Method::HEAD;
+
+    let mut resp = next.run(req).await?;
+
+    if !self.is_compressible(&resp) {
+      return Ok(resp);
+    }
+    let varies = resp
+      .headers()
+      .get_all(VARY)
+      .iter()
+      .filter_map(|raw| raw.to_str().ok())
+      .flat_map(|raw| raw.split(','))
+      .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+      resp
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    let too_small = resp
+      .headers()
+      .get(CONTENT_LENGTH)
+      .and_then(|raw| raw.to_str().ok()?.parse::<u64>().ok())
+      .is_some_and(|length| length < self.min_size);
+    let no_transform = resp
+      .headers()
+      .get(CACHE_CONTROL)
+      .and_then(|raw| raw.to_str().ok())
+      .is_some_and(|raw| raw.to_ascii_lowercase().contains("no-transform"));
+
+    let encoding = match encoding {
+      Some(encoding)
+        if !is_head
+          && !too_small
+          && !no_transform
+          && resp.status() != StatusCode::PARTIAL_CONTENT
+          && resp.status() != StatusCode::NO_CONTENT
+          && resp.status() != StatusCode::NOT_MODIFIED
+          && !resp.headers().contains_key(CONTENT_ENCODING) =>
+      {
+        encoding
+      }
+      _ => return Ok(resp),
+    };
+
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(ACCEPT_RANGES);
+    // the representation changed, so a strong validator would be wrong
+    if let Some(etag) = headers.get(ETAG).and_then(|raw| raw.to_str().ok()) {
+      if !etag.starts_with("W/") {
+        if let Ok(weak) = HeaderValue::try_from(format!("W/{}", etag)) {
+          headers.insert(ETAG, weak);
+        }
+      }
+    }
+
+    Ok(resp.map(|body| compress(body, encoding, self.level())))
+  }
+}
+
+fn compress(body: Body, encoding: Encoding, level: Level) -> Body {
+  let reader = StreamReader::new(body.map_err(std::io::Error::other));
+
+  let encoder: Box<dyn AsyncRead + Send + Unpin> = match encoding {
+    Encoding::Zstd => Box::new(ZstdEncoder::with_quality(reader, level)),
+    Encoding::Brotli => Box::new(BrotliEncoder::with_quality(reader, level)),
+    Encoding::Gzip => Box::new(GzipEncoder::with_quality(reader, level)),
+  };
+
+  Body::wrap_stream(ReaderStream::new(encoder))
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::header::RANGE;
+  use hyper::http;
+
+  use super::*;
+  use crate::middleware::testing::{route, run};
+
+  const BODY: &str = "compressible text, compressible text, compressible text";
+
+  fn compression() -> Compression {
+    Compression::new(
+      vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip],
+      16,
+      vec!["text/".to_string(), "application/json".to_string()],
+      None,
+    )
+  }
+
+  fn negotiated(accept: &'static str) -> Option<&'static str> {
+    compression()
+      .negotiate(Some(&HeaderValue::from_static(accept)))
+      .map(|encoding| encoding.name())
+  }
+
+  fn request(accept: &str) -> Request<Body> {
+    Request::builder()
+      .header(ACCEPT_ENCODING, accept)
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  async fn respond(resp: fn() -> http::response::Builder, req: Request<Body>) -> Response<Body> {
+    let route = route(move |_| Ok(resp().body(Body::from(BODY))?));
+    run(&compression(), &route, req).await.unwrap()
+  }
+
+  fn text() -> http::response::Builder {
+    Response::builder()
+      .header(CONTENT_TYPE, "text/html; charset=utf-8")
+      .header(CONTENT_LENGTH, BODY.len())
+      .header(ETAG, "\"v1\"")
+  }
+
+  #[test]
+  fn negotiates_by_quality() {
+    assert_eq!(negotiated("gzip, deflate, br, zstd"), Some("zstd"));
+    assert_eq!(negotiated("gzip;q=1.0, br;q=0.8"), Some("gzip"));
+    assert_eq!(negotiated("GZIP"), Some("gzip"));
+    assert_eq!(negotiated("br;q=0, gzip;q=0.5"), Some("gzip"));
+    assert_eq!(negotiated("*;q=0.5, zstd;q=0"), Some("br"));
+    assert_eq!(negotiated("identity;q=0, gzip"), Some("gzip"));
+    assert_eq!(negotiated("identity;q=0"), None);
+    assert_eq!(negotiated("deflate"), None);
+    assert_eq!(negotiated("gzip;q=0"), None);
+    assert!(compression().negotiate(None).is_none());
+  }
+
+  #[test]
+  fn matches_mime_types_case_insensitively() {
+    let compressible = |content_type: &str| {
+      let resp = Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::empty())
+        .unwrap();
+      compression().is_compressible(&resp)
+    };
+    assert!(compressible("text/html"));
+    assert!(compressible("Text/HTML; charset=utf-8"));
+    assert!(compressible("APPLICATION/JSON"));
+    assert!(!compressible("text/event-stream"));
+    assert!(!compressible("Text/Event-Stream"));
+    assert!(!compressible("image/png"));
+    assert!(!compressible("te"));
+  }
+
+  #[tokio::test]
+  async fn compresses_and_weakens_etag() {
+    let resp = respond(text, request("gzip")).await;
+    let headers = resp.headers();
+    assert_eq!(headers[CONTENT_ENCODING], "gzip");
+    assert_eq!(headers[ETAG], "W/\"v1\"");
+    assert_eq!(headers[VARY], "accept-encoding");
+    assert!(!headers.contains_key(CONTENT_LENGTH));
+
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body[..2], [0x1f, 0x8b]);
+  }
+
+  #[tokio::test]
+  async fn keeps_weak_etag() {
+    let weak = || {
+      Response::builder()
+        .header(CONTENT_TYPE, "text/plain")
+        .header(ETAG, "W/\"v1\"")
+    };
+    let resp = respond(weak, request("gzip")).await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(resp.headers()[ETAG], "W/\"v1\"");
+  }
+
+  #[tokio::test]
+  async fn skips_encoded_responses() {
+    let encoded = || text().header(CONTENT_ENCODING, "br");
+    let resp = respond(encoded, request("gzip")).await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "br");
+    assert_eq!(resp.headers()[ETAG], "\"v1\"");
+  }
+
+  #[tokio::test]
+  async fn skips_tiny_bodies() {
+    let tiny = || {
+      Response::builder()
+        .header(CONTENT_TYPE, "text/plain")
+        .header(CONTENT_LENGTH, 8)
+    };
+    let resp = respond(tiny, request("gzip")).await;
+    assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+    assert_eq!(resp.headers()[VARY], "accept-encoding");
+  }
+
+  #[tokio::test]
+  async fn skips_partial_content() {
+    let partial = || text().status(StatusCode::PARTIAL_CONTENT);
+    let mut req = request("gzip");
+    req
+      .headers_mut()
+      .insert(RANGE, HeaderValue::from_static("bytes=0-9"));
+    let resp = respond(partial, req).await;
+    assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+    assert_eq!(
+      resp.headers()[CONTENT_LENGTH],
+      BODY.len().to_string().as_str()
+    );
+  }
+
+  #[tokio::test]
+  async fn skips_clients_without_encodings() {
+    let resp = respond(text, request("identity")).await;
+    assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+    assert_eq!(resp.headers()[ETAG], "\"v1\"");
+  }
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::limiter::Limiter;
+use crate::middleware::{Middleware, Next};
+use crate::PuxResult;
+
+/// Caps the in-flight requests of a route, rejecting overflowing requests with 503.
+pub(crate) struct ConcurrencyLimit {
+  limiter: Arc<Limiter>,
+}
+
+impl ConcurrencyLimit {
+  pub(crate) fn new(limiter: Arc<Limiter>) -> Self {
+    Self { limiter }
+  }
+}
+
+#[async_trait]
+impl Middleware for ConcurrencyLimit {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let mut permit = match self.limiter.acquire().await {
+      Some(permit) => permit,
+      None => return Err(StatusCode::SERVICE_UNAVAILABLE.into()),
+    };
+
+    let result = next.run(req).await;
+    permit.complete(matches!(&result, Ok(resp) if !resp.status().is_server_error()));
+    result.map(|resp| permit.attach(resp))
+  }
+}
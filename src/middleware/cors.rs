@@ -0,0 +1,426 @@
+use async_trait::async_trait;
+use hyper::header::{
+  HeaderName, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+  ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+  ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+  VARY,
+};
+use hyper::http::HeaderValue;
+use hyper::{http, Body, HeaderMap, Method, Request, Response, StatusCode};
+use regex::Regex;
+
+use crate::error::PuxError;
+use crate::middleware::{Middleware, Next};
+use crate::PuxResult;
+
+pub(crate) enum AllowedOrigin {
+  Any,
+  Exact(String),
+  /// `https://*.example.com` matches every subdomain, but not the domain itself.
+  Subdomain(String, String),
+  Regex(Regex),
+}
+
+/// Answers preflight requests directly and adds `Access-Control-*` headers to responses for
+/// allowed origins.
+pub(crate) struct Cors {
+  origins: Vec<AllowedOrigin>,
+  methods: Vec<Method>,
+  /// Empty allows all requested headers.
+  headers: Vec<HeaderName>,
+  expose_headers: Vec<HeaderName>,
+  credentials: bool,
+  max_age: Option<u64>,
+}
+
+impl AllowedOrigin {
+  pub(crate) fn parse(raw: &str) -> Self {
+    if raw == "*" {
+      return Self::Any;
+    }
+
+    match raw.split_once("://*.") {
+      Some((scheme, domain)) => Self::Subdomain(format!("{}://", scheme), format!(".{}", domain)),
+      None => Self::Exact(raw.to_string()),
+    }
+  }
+
+  /// The expression has to match the whole origin.
+  pub(crate) fn regex(raw: &str) -> PuxResult<Self> {
+    Regex::new(&format!("^(?:{})$", raw))
+      .map(Self::Regex)
+      .map_err(|err| PuxError::Config(format!("invalid cors origin regex {}: {}", raw, err)))
+  }
+
+  fn matches(&self, origin: &str) -> bool {
+    match self {
+      Self::Any => true,
+      Self::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+      Self::Subdomain(scheme, suffix) => origin
+        .strip_prefix(scheme.as_str())
+        .and_then(|host| host.strip_suffix(suffix.as_str()))
+        .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains('/')),
+      Self::Regex(regex) => regex.is_match(origin),
+    }
+  }
+}
+
+impl Cors {
+  /// Fails for a wildcard origin with credentials, every origin would be reflected.
+  pub(crate) fn new(
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<u64>,
+  ) -> PuxResult<Self> {
+    if credentials
+      && origins
+        .iter()
+        .any(|origin| matches!(origin, AllowedOrigin::Any))
+    {
+      return Err(PuxError::Config(
+        "cors origin * can not be combined with credentials".to_string(),
+      ));
+    }
+
+    Ok(Self {
+      origins,
+      methods,
+      headers,
+      expose_headers,
+      credentials,
+      max_age,
+    })
+  }
+
+  /// The value for `Access-Control-Allow-Origin` if the origin is allowed.
+  fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+    let raw = origin.to_str().ok()?;
+    let allowed = self.origins.iter().find(|allowed| allowed.matches(raw))?;
+
+    match allowed {
+      AllowedOrigin::Any => Some(HeaderValue::from_static("*")),
+      _ => Some(origin.clone()),
+    }
+  }
+
+  fn insert_common(&self, headers: &mut HeaderMap, allow_origin: HeaderValue) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if self.credentials {
+      headers.insert(
+        ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        HeaderValue::from_static("true"),
+      );
+    }
+  }
+
+  /// The headers for responses to actual requests, also added to error pages.
+  fn response_headers(&self, allow_origin: Option<HeaderValue>) -> PuxResult<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    // the response differs per origin unless every origin is allowed, caches have to know
+    if !matches!(&allow_origin, Some(allow_origin) if allow_origin == "*") {
+      headers.insert(VARY, HeaderValue::from_static("origin"));
+    }
+
+    if let Some(allow_origin) = allow_origin {
+      self.insert_common(&mut headers, allow_origin);
+      if !self.expose_headers.is_empty() {
+        headers.insert(
+          ACCESS_CONTROL_EXPOSE_HEADERS,
+          HeaderValue::try_from(join(self.expose_headers.iter().map(HeaderName::as_str)))
+            .map_err(http::Error::from)?,
+        );
+      }
+    }
+
+    Ok(headers)
+  }
+
+  fn preflight(&self, req: &Request<Body>) -> PuxResult<Response<Body>> {
+    let mut resp = Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .body(Body::empty())?;
+    let headers = resp.headers_mut();
+    headers.insert(
+      VARY,
+      HeaderValue::from_static(
+        "origin, access-control-request-method, access-control-request-headers",
+      ),
+    );
+
+    let allow_origin = match req
+      .headers()
+      .get(ORIGIN)
+      .and_then(|origin| self.allow_origin(origin))
+    {
+      Some(allow_origin) => allow_origin,
+      None => return Ok(resp),
+    };
+
+    let method_allowed = req
+      .headers()
+      .get(ACCESS_CONTROL_REQUEST_METHOD)
+      .and_then(|raw| Method::from_bytes(raw.as_bytes()).ok())
+      .is_some_and(|method| self.methods.contains(&method));
+
+    let requested_headers = req
+      .headers()
+      .get(ACCESS_CONTROL_REQUEST_HEADERS)
+      .and_then(|raw| raw.to_str().ok())
+      .unwrap_or_default();
+    let headers_allowed = self.headers.is_empty()
+      || requested_headers
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .all(|name| {
+          self
+            .headers
+            .iter()
+            .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+        });
+
+    if !method_allowed || !headers_allowed {
+      return Ok(resp);
+    }
+
+    self.insert_common(headers, allow_origin);
+    headers.insert(
+      ACCESS_CONTROL_ALLOW_METHODS,
+      HeaderValue::try_from(join(self.methods.iter().map(Method::as_str)))
+        .map_err(http::Error::from)?,
+    );
+    if !requested_headers.is_empty() {
+      let allowed = if self.headers.is_empty() {
+        requested_headers.to_string()
+      } else {
+        join(self.headers.iter().map(HeaderName::as_str))
+      };
+      headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::try_from(allowed).map_err(http::Error::from)?,
+      );
+    }
+    if let Some(max_age) = self.max_age {
+      headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+    }
+
+    Ok(resp)
+  }
+}
+
+#[async_trait]
+impl Middleware for Cors {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+      return self.preflight(&req);
+    }
+
+    let allow_origin = req
+      .headers()
+      .get(ORIGIN)
+      .and_then(|origin| self.allow_origin(origin));
+
+    let headers = self.response_headers(allow_origin)?;
+    match next.run(req).await {
+      Ok(mut resp) => {
+        merge(resp.headers_mut(), &headers);
+        Ok(resp)
+      }
+      Err(PuxError::Status(code)) => Err(PuxError::StatusWithHeaders(code, headers)),
+      Err(PuxError::StatusWithHeaders(code, mut extra)) => {
+        merge(&mut extra, &headers);
+        Err(PuxError::StatusWithHeaders(code, extra))
+      }
+      Err(err) => Err(err),
+    }
+  }
+}
+
+/// Adds the headers, `Vary` is appended to the values already present.
+fn merge(target: &mut HeaderMap, headers: &HeaderMap) {
+  for (name, value) in headers {
+    if name == VARY {
+      target.append(name, value.clone());
+    } else {
+      target.insert(name, value.clone());
+    }
+  }
+}
+
+fn join<'a>(values: impl Iterator<Item = &'a str>) -> String {
+  values.collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::middleware::testing::{route, run};
+
+  fn cors(origins: &[&str], credentials: bool) -> PuxResult<Cors> {
+    Cors::new(
+      origins
+        .iter()
+        .map(|raw| AllowedOrigin::parse(raw))
+        .collect(),
+      vec![Method::GET, Method::POST],
+      vec![],
+      vec![],
+      credentials,
+      None,
+    )
+  }
+
+  fn allow_origin(cors: &Cors, origin: &'static str) -> Option<HeaderValue> {
+    cors.allow_origin(&HeaderValue::from_static(origin))
+  }
+
+  #[test]
+  fn rejects_wildcard_with_credentials() {
+    assert!(cors(&["*"], true).is_err());
+    assert!(cors(&["https://example.com", "*"], true).is_err());
+  }
+
+  #[test]
+  fn wildcard_without_credentials() {
+    let cors = cors(&["*"], false).unwrap();
+    assert_eq!(
+      allow_origin(&cors, "https://example.com").unwrap(),
+      HeaderValue::from_static("*")
+    );
+  }
+
+  #[test]
+  fn reflects_listed_origins_with_credentials() {
+    let cors = cors(&["https://example.com", "https://*.example.org"], true).unwrap();
+    assert_eq!(
+      allow_origin(&cors, "https://example.com").unwrap(),
+      "https://example.com"
+    );
+    assert_eq!(
+      allow_origin(&cors, "https://api.example.org").unwrap(),
+      "https://api.example.org"
+    );
+    assert!(allow_origin(&cors, "https://example.org").is_none());
+    assert!(allow_origin(&cors, "https://evil.com").is_none());
+    assert!(allow_origin(&cors, "http://api.example.org").is_none());
+  }
+
+  #[test]
+  fn preflight_with_credentials() {
+    let cors = cors(&["https://example.com"], true).unwrap();
+    let req = Request::builder()
+      .method(Method::OPTIONS)
+      .header(ORIGIN, "https://example.com")
+      .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+      .body(Body::empty())
+      .unwrap();
+    let resp = cors.preflight(&req).unwrap();
+    let headers = resp.headers();
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+  }
+
+  #[test]
+  fn preflight_for_unknown_origin() {
+    let cors = cors(&["https://example.com"], true).unwrap();
+    let req = Request::builder()
+      .method(Method::OPTIONS)
+      .header(ORIGIN, "https://evil.com")
+      .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+      .body(Body::empty())
+      .unwrap();
+    let resp = cors.preflight(&req).unwrap();
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+  }
+
+  #[test]
+  fn anchors_regex_origins() {
+    let cors = Cors::new(
+      vec![AllowedOrigin::regex(r"https://.*\.m4rc3l\.de").unwrap()],
+      vec![Method::GET],
+      vec![],
+      vec![],
+      true,
+      None,
+    )
+    .unwrap();
+    assert!(allow_origin(&cors, "https://x.m4rc3l.de").is_some());
+    assert!(allow_origin(&cors, "https://x.m4rc3l.de.evil.com").is_none());
+    assert!(allow_origin(&cors, "evil://https://x.m4rc3l.de").is_none());
+  }
+
+  #[test]
+  fn rejects_invalid_regex_origins() {
+    assert!(AllowedOrigin::regex("https://(").is_err());
+  }
+
+  fn request(origin: &'static str) -> Request<Body> {
+    Request::builder()
+      .header(ORIGIN, origin)
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn adds_headers_to_responses() {
+    let cors = cors(&["https://example.com"], true).unwrap();
+    let route = route(|_| {
+      Ok(
+        Response::builder()
+          .header(VARY, "accept-encoding")
+          .body(Body::empty())?,
+      )
+    });
+
+    let resp = run(&cors, &route, request("https://example.com"))
+      .await
+      .unwrap();
+    let headers = resp.headers();
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    let vary = headers.get_all(VARY).iter().collect::<Vec<_>>();
+    assert_eq!(vary, ["accept-encoding", "origin"]);
+
+    let resp = run(&cors, &route, request("https://evil.com"))
+      .await
+      .unwrap();
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+  }
+
+  #[tokio::test]
+  async fn adds_headers_to_errors() {
+    let cors = cors(&["https://example.com"], false).unwrap();
+
+    let unauthorized = route(|_| Err(PuxError::Status(StatusCode::UNAUTHORIZED)));
+    match run(&cors, &unauthorized, request("https://example.com")).await {
+      Err(PuxError::StatusWithHeaders(code, headers)) => {
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[VARY], "origin");
+      }
+      _ => panic!("expected an error with headers"),
+    }
+
+    let limited = route(|_| {
+      let mut headers = HeaderMap::new();
+      headers.insert(hyper::header::RETRY_AFTER, HeaderValue::from(1));
+      Err(PuxError::StatusWithHeaders(
+        StatusCode::TOO_MANY_REQUESTS,
+        headers,
+      ))
+    });
+    match run(&cors, &limited, request("https://example.com")).await {
+      Err(PuxError::StatusWithHeaders(code, headers)) => {
+        assert_eq!(code, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[hyper::header::RETRY_AFTER], "1");
+      }
+      _ => panic!("expected an error with headers"),
+    }
+  }
+}
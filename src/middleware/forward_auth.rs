@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::header::{HeaderName, CONNECTION, HOST};
+use hyper::{Body, Method, Request, Response};
+
+use crate::entrypoint::ConnInfo;
+use crate::handler::{request_host, RequestId, X_REQUEST_ID};
+use crate::middleware::{Middleware, Next};
+use crate::upstream::Upstream;
+use crate::PuxResult;
+
+const X_FORWARDED_METHOD: HeaderName = HeaderName::from_static("x-forwarded-method");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_URI: HeaderName = HeaderName::from_static("x-forwarded-uri");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Asks an external service like Authelia or oauth2-proxy whether a request may pass. The
+/// original request is described by `X-Forwarded-*` headers of a `GET` subrequest to `path`.
+pub(crate) struct ForwardAuth {
+  upstream: Arc<Upstream>,
+  path: String,
+  /// Copied from the original request to the subrequest.
+  request_headers: Vec<HeaderName>,
+  /// Copied from a successful auth response to the original request.
+  response_headers: Vec<HeaderName>,
+}
+
+impl ForwardAuth {
+  pub(crate) fn new(
+    upstream: Arc<Upstream>,
+    path: String,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+  ) -> Self {
+    Self {
+      upstream,
+      path,
+      request_headers,
+      response_headers,
+    }
+  }
+
+  fn subrequest(&self, req: &Request<Body>) -> PuxResult<Request<Body>> {
+    let conn_info = req.extensions().get::<ConnInfo>();
+    let proto = match conn_info {
+      Some(conn_info) if conn_info.tls => "https",
+      _ => "http",
+    };
+    let uri = req
+      .uri()
+      .path_and_query()
+      .map(|path_and_query| path_and_query.as_str())
+      .unwrap_or("/");
+
+    let mut builder = Request::builder()
+      .method(Method::GET)
+      .uri(self.path.as_str())
+      .header(CONNECTION, "keep-alive")
+      .header(X_FORWARDED_METHOD, req.method().as_str())
+      .header(X_FORWARDED_PROTO, proto)
+      .header(X_FORWARDED_URI, uri);
+
+    if let Some(host) = request_host(req) {
+      builder = builder.header(HOST, host).header(X_FORWARDED_HOST, host);
+    }
+    if let Some(conn_info) = conn_info {
+      builder = builder.header(X_FORWARDED_FOR, conn_info.peer_addr.ip().to_string());
+    }
+    if let Some(RequestId(id)) = req.extensions().get() {
+      builder = builder.header(X_REQUEST_ID, id);
+    }
+
+    for name in &self.request_headers {
+      for value in req.headers().get_all(name) {
+        builder = builder.header(name, value);
+      }
+    }
+
+    Ok(builder.body(Body::empty())?)
+  }
+}
+
+#[async_trait]
+impl Middleware for ForwardAuth {
+  async fn handle(&self, mut req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let resp = self.upstream.send(self.subrequest(&req)?).await?;
+
+    if !resp.status().is_success() {
+      return Ok(resp);
+    }
+
+    for name in &self.response_headers {
+      req.headers_mut().remove(name);
+      for value in resp.headers().get_all(name) {
+        req.headers_mut().append(name.clone(), value.clone());
+      }
+    }
+
+    next.run(req).await
+  }
+}
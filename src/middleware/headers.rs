@@ -0,0 +1,259 @@
+use async_trait::async_trait;
+use hyper::header::HeaderName;
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response};
+use tracing::warn;
+
+use crate::entrypoint::ConnInfo;
+use crate::handler::{request_host, RequestId};
+use crate::middleware::{Middleware, Next};
+use crate::path::PathParams;
+use crate::template;
+use crate::PuxResult;
+
+/// Sets, appends or removes request headers before forwarding and response headers before
+/// returning. Values may contain `{client_ip}`, `{host}`, `{scheme}`, `{request_id}`, `{route}`,
+/// `{tls_version}` and the parameters captured by the route path.
+pub(crate) struct Headers {
+  request: HeaderRules,
+  response: HeaderRules,
+}
+
+pub(crate) struct HeaderRules {
+  pub(crate) set: Vec<(HeaderName, String)>,
+  pub(crate) append: Vec<(HeaderName, String)>,
+  pub(crate) remove: Vec<HeaderName>,
+}
+
+/// Values available to templates, captured from the request.
+struct Context {
+  values: [(&'static str, String); 6],
+  params: Option<PathParams>,
+}
+
+impl Headers {
+  pub(crate) fn new(request: HeaderRules, response: HeaderRules) -> Self {
+    Self { request, response }
+  }
+}
+
+#[async_trait]
+impl Middleware for Headers {
+  async fn handle(&self, mut req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let context = Context::new(&req, &next.route().name);
+
+    self.request.apply(req.headers_mut(), &context);
+    let mut resp = next.run(req).await?;
+    self.response.apply(resp.headers_mut(), &context);
+
+    Ok(resp)
+  }
+}
+
+impl HeaderRules {
+  fn apply(&self, headers: &mut HeaderMap, context: &Context) {
+    for name in &self.remove {
+      headers.remove(name);
+    }
+
+    for (name, template) in &self.set {
+      if let Some(value) = context.render(template) {
+        headers.insert(name.clone(), value);
+      }
+    }
+
+    for (name, template) in &self.append {
+      if let Some(value) = context.render(template) {
+        headers.append(name.clone(), value);
+      }
+    }
+  }
+}
+
+impl Context {
+  fn new(req: &Request<Body>, route: &str) -> Self {
+    let conn_info = req.extensions().get::<ConnInfo>();
+
+    Self {
+      values: [
+        (
+          "client_ip",
+          conn_info
+            .map(|conn_info| conn_info.peer_addr.ip().to_string())
+            .unwrap_or_default(),
+        ),
+        ("host", request_host(req).unwrap_or_default().to_string()),
+        (
+          "scheme",
+          match conn_info {
+            Some(conn_info) if conn_info.tls => "https".to_string(),
+            _ => "http".to_string(),
+          },
+        ),
+        (
+          "request_id",
+          req
+            .extensions()
+            .get::<RequestId>()
+            .and_then(|id| id.0.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        ),
+        ("route", route.to_string()),
+        (
+          "tls_version",
+          conn_info
+            .and_then(|conn_info| conn_info.tls_version)
+            .unwrap_or_default()
+            .to_string(),
+        ),
+      ],
+      params: req.extensions().get::<PathParams>().cloned(),
+    }
+  }
+
+  fn render(&self, template: &str) -> Option<HeaderValue> {
+    let value = template::render(template, |name, value| {
+      let replacement = self
+        .values
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.as_str())
+        .or_else(|| self.params.as_ref()?.get(name));
+      replacement
+        .map(|replacement| value.push_str(replacement))
+        .is_some()
+    });
+
+    match HeaderValue::try_from(value) {
+      Ok(value) => Some(value),
+      Err(err) => {
+        warn!("Invalid header value from template {}: {}", template, err);
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::header::HOST;
+
+  use super::*;
+  use crate::middleware::testing::{route, run};
+  use crate::path::PathPattern;
+
+  fn rules(set: &[(&str, &str)], append: &[(&str, &str)], remove: &[&str]) -> HeaderRules {
+    let pairs = |pairs: &[(&str, &str)]| {
+      pairs
+        .iter()
+        .map(|(name, value)| {
+          (
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.to_string(),
+          )
+        })
+        .collect()
+    };
+    HeaderRules {
+      set: pairs(set),
+      append: pairs(append),
+      remove: remove
+        .iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).unwrap())
+        .collect(),
+    }
+  }
+
+  fn request() -> Request<Body> {
+    let mut req = Request::builder()
+      .uri("/users/42")
+      .header(HOST, "example.com:8443")
+      .header("x-debug", "1")
+      .header("x-tag", "a")
+      .body(Body::empty())
+      .unwrap();
+    req.extensions_mut().insert(ConnInfo {
+      peer_addr: "192.0.2.1:4711".parse().unwrap(),
+      tls: true,
+      tls_version: Some("TLSv1.3"),
+      sni: None,
+    });
+    req
+      .extensions_mut()
+      .insert(RequestId(HeaderValue::from_static("abc")));
+    let params = PathPattern::parse("/users/{id}", false)
+      .matches("/users/42")
+      .unwrap();
+    req.extensions_mut().insert(params);
+    req
+  }
+
+  #[tokio::test]
+  async fn changes_request_headers() {
+    let headers = Headers::new(
+      rules(
+        &[("x-forwarded-proto", "{scheme}"), ("x-tag", "b")],
+        &[("x-tag", "c")],
+        &["x-debug"],
+      ),
+      rules(&[], &[], &[]),
+    );
+    let route = route(|req| {
+      let headers = req.headers();
+      assert!(!headers.contains_key("x-debug"));
+      assert_eq!(headers["x-forwarded-proto"], "https");
+      let tags = headers.get_all("x-tag").iter().collect::<Vec<_>>();
+      assert_eq!(tags, ["b", "c"]);
+      Ok(Response::new(Body::empty()))
+    });
+
+    assert!(run(&headers, &route, request()).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn changes_response_headers() {
+    let headers = Headers::new(
+      rules(&[], &[], &[]),
+      rules(
+        &[("x-route", "{route}")],
+        &[("x-served-by", "pux")],
+        &["server"],
+      ),
+    );
+    let route = route(|_| {
+      Ok(
+        Response::builder()
+          .header("server", "backend")
+          .header("x-served-by", "backend")
+          .body(Body::empty())?,
+      )
+    });
+
+    let resp = run(&headers, &route, request()).await.unwrap();
+    let headers = resp.headers();
+    assert!(!headers.contains_key("server"));
+    assert_eq!(headers["x-route"], "test");
+    let served_by = headers.get_all("x-served-by").iter().collect::<Vec<_>>();
+    assert_eq!(served_by, ["backend", "pux"]);
+  }
+
+  #[test]
+  fn expands_templates() {
+    let context = Context::new(&request(), "api");
+    let render = |template| context.render(template).unwrap();
+    assert_eq!(
+      render("{client_ip} {host} {scheme} {request_id} {route} {tls_version}"),
+      "192.0.2.1 example.com https abc api TLSv1.3"
+    );
+    assert_eq!(render("user-{id}"), "user-42");
+    assert_eq!(render("{unknown} {id"), "{unknown} {id");
+    assert!(context.render("line\nbreak").is_none());
+
+    let context = Context::new(&Request::new(Body::empty()), "api");
+    assert_eq!(
+      context.render("[{client_ip}] {scheme} {id}").unwrap(),
+      "[] http {id}"
+    );
+  }
+}
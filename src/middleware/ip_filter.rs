@@ -0,0 +1,354 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use hyper::header::HeaderName;
+use hyper::{Body, Request, Response, StatusCode};
+use ipnet::IpNet;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::entrypoint::ConnInfo;
+use crate::error::PuxError;
+use crate::middleware::{Middleware, Next};
+use crate::PuxResult;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Allows or denies requests by client address, deny entries take precedence and a configured
+/// allow list rejects everything not on it, even when its files are empty.
+pub(crate) struct IpFilter {
+  allow: NetList,
+  deny: NetList,
+  trusted_proxies: Vec<IpNet>,
+}
+
+/// Inline entries plus entries read from files, the files are reloaded when they change.
+pub(crate) struct NetList {
+  inline: Vec<IpNet>,
+  files: Vec<PathBuf>,
+  nets: RwLock<Vec<IpNet>>,
+}
+
+impl IpFilter {
+  pub(crate) fn new(allow: NetList, deny: NetList, trusted_proxies: Vec<IpNet>) -> Arc<Self> {
+    let filter = Arc::new(Self {
+      allow,
+      deny,
+      trusted_proxies,
+    });
+
+    if !filter.allow.files.is_empty() || !filter.deny.files.is_empty() {
+      let filter = filter.clone();
+      tokio::spawn(async move {
+        let mut modified = (filter.allow.modified(), filter.deny.modified());
+        loop {
+          sleep(Duration::from_secs(2)).await;
+          let current = (filter.allow.modified(), filter.deny.modified());
+          if current != modified {
+            modified = current;
+            filter.allow.reload();
+            filter.deny.reload();
+            info!("Reloaded ip filter lists");
+          }
+        }
+      });
+    }
+
+    filter
+  }
+
+  fn is_allowed(&self, ip: IpAddr) -> bool {
+    if self.deny.contains(ip) {
+      return false;
+    }
+    !self.allow.is_configured() || self.allow.contains(ip)
+  }
+}
+
+#[async_trait]
+impl Middleware for IpFilter {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    match client_ip(&req, &self.trusted_proxies) {
+      Some(ip) if self.is_allowed(ip) => next.run(req).await,
+      ip => {
+        debug!("Denied request from {:?}", ip);
+        Err(StatusCode::FORBIDDEN.into())
+      }
+    }
+  }
+}
+
+impl NetList {
+  /// Fails if one of the files can't be read.
+  pub(crate) fn new(inline: Vec<IpNet>, files: Vec<PathBuf>) -> PuxResult<Self> {
+    let list = Self {
+      inline,
+      files,
+      nets: RwLock::new(Vec::new()),
+    };
+    *list.nets.write().unwrap() = list.load().map_err(|(file, err)| {
+      PuxError::Config(format!("Unable to read ip list {:?}: {}", file, err))
+    })?;
+    Ok(list)
+  }
+
+  /// Keeps the previous entries if a file can't be read.
+  fn reload(&self) {
+    match self.load() {
+      Ok(nets) => *self.nets.write().unwrap() = nets,
+      Err((file, err)) => warn!("Unable to read ip list {:?}: {}", file, err),
+    }
+  }
+
+  fn load(&self) -> Result<Vec<IpNet>, (&PathBuf, io::Error)> {
+    let mut nets = self.inline.clone();
+    for file in &self.files {
+      let content = std::fs::read_to_string(file).map_err(|err| (file, err))?;
+      nets.extend(content.lines().filter_map(|line| {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+          return None;
+        }
+        let net = parse_net(line);
+        if net.is_none() {
+          warn!("Ignoring invalid address {} in {:?}", line, file);
+        }
+        net
+      }));
+    }
+    Ok(nets)
+  }
+
+  fn modified(&self) -> Vec<Option<SystemTime>> {
+    self
+      .files
+      .iter()
+      .map(|file| {
+        std::fs::metadata(file)
+          .and_then(|meta| meta.modified())
+          .ok()
+      })
+      .collect()
+  }
+
+  fn is_configured(&self) -> bool {
+    !self.inline.is_empty() || !self.files.is_empty()
+  }
+
+  fn contains(&self, ip: IpAddr) -> bool {
+    self
+      .nets
+      .read()
+      .unwrap()
+      .iter()
+      .any(|net| net.contains(&ip))
+  }
+}
+
+/// The peer address, or if the peer is a trusted proxy the right most untrusted address of the
+/// `X-Forwarded-For` chain.
+pub(crate) fn client_ip(req: &Request<Body>, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+  let mut ip = req.extensions().get::<ConnInfo>()?.peer_addr.ip();
+  let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+  if !is_trusted(&ip) {
+    return Some(ip);
+  }
+
+  let forwarded = req
+    .headers()
+    .get_all(X_FORWARDED_FOR)
+    .iter()
+    .filter_map(|raw| raw.to_str().ok())
+    .flat_map(|raw| raw.split(','))
+    .collect::<Vec<_>>();
+
+  for hop in forwarded.iter().rev() {
+    match hop.trim().parse::<IpAddr>().map(|hop| hop.to_canonical()) {
+      Ok(hop) => {
+        ip = hop;
+        if !is_trusted(&hop) {
+          break;
+        }
+      }
+      Err(_) => break,
+    }
+  }
+
+  Some(ip)
+}
+
+/// Parses a network in CIDR notation or a single address.
+pub(crate) fn parse_net(raw: &str) -> Option<IpNet> {
+  raw
+    .parse::<IpNet>()
+    .ok()
+    .or_else(|| raw.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn net(raw: &str) -> IpNet {
+    parse_net(raw).unwrap()
+  }
+
+  fn ip(raw: &str) -> IpAddr {
+    raw.parse().unwrap()
+  }
+
+  fn filter(allow: NetList, deny: NetList) -> IpFilter {
+    IpFilter {
+      allow,
+      deny,
+      trusted_proxies: Vec::new(),
+    }
+  }
+
+  fn file(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("pux-{}-{}", std::process::id(), name));
+    std::fs::write(&path, content).unwrap();
+    path
+  }
+
+  fn request(peer_addr: &str, forwarded: &[&str]) -> Request<Body> {
+    let mut req = Request::new(Body::empty());
+    for hops in forwarded {
+      req
+        .headers_mut()
+        .append(X_FORWARDED_FOR, hops.parse().unwrap());
+    }
+    req.extensions_mut().insert(ConnInfo {
+      peer_addr: peer_addr.parse().unwrap(),
+      tls: false,
+      tls_version: None,
+      sni: None,
+    });
+    req
+  }
+
+  #[test]
+  fn allows_everything_without_lists() {
+    let filter = filter(
+      NetList::new(vec![], vec![]).unwrap(),
+      NetList::new(vec![], vec![]).unwrap(),
+    );
+    assert!(filter.is_allowed(ip("192.0.2.1")));
+    assert!(filter.is_allowed(ip("2001:db8::1")));
+  }
+
+  #[test]
+  fn deny_takes_precedence() {
+    let filter = filter(
+      NetList::new(vec![net("10.0.0.0/8")], vec![]).unwrap(),
+      NetList::new(vec![net("10.0.0.1")], vec![]).unwrap(),
+    );
+    assert!(filter.is_allowed(ip("10.1.2.3")));
+    assert!(!filter.is_allowed(ip("10.0.0.1")));
+    assert!(!filter.is_allowed(ip("192.0.2.1")));
+  }
+
+  #[test]
+  fn deny_only() {
+    let filter = filter(
+      NetList::new(vec![], vec![]).unwrap(),
+      NetList::new(vec![net("192.0.2.0/24")], vec![]).unwrap(),
+    );
+    assert!(!filter.is_allowed(ip("192.0.2.1")));
+    assert!(filter.is_allowed(ip("198.51.100.1")));
+  }
+
+  #[test]
+  fn file_only_allow_list() {
+    let path = file(
+      "allow",
+      "# office\n192.0.2.0/24 # vpn\n\ninvalid\n2001:db8::1\n",
+    );
+    let filter = filter(
+      NetList::new(vec![], vec![path.clone()]).unwrap(),
+      NetList::new(vec![], vec![]).unwrap(),
+    );
+    assert!(filter.is_allowed(ip("192.0.2.42")));
+    assert!(filter.is_allowed(ip("2001:db8::1")));
+    assert!(!filter.is_allowed(ip("198.51.100.1")));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn empty_allow_file_denies_everything() {
+    let path = file("empty", "");
+    let filter = filter(
+      NetList::new(vec![], vec![path.clone()]).unwrap(),
+      NetList::new(vec![], vec![]).unwrap(),
+    );
+    assert!(!filter.is_allowed(ip("192.0.2.1")));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn file_only_deny_list() {
+    let path = file("deny", "198.51.100.0/24\n");
+    let filter = filter(
+      NetList::new(vec![], vec![]).unwrap(),
+      NetList::new(vec![], vec![path.clone()]).unwrap(),
+    );
+    assert!(!filter.is_allowed(ip("198.51.100.7")));
+    assert!(filter.is_allowed(ip("192.0.2.1")));
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn rejects_unreadable_files() {
+    let path = std::env::temp_dir().join("pux-missing-ip-list");
+    assert!(NetList::new(vec![], vec![path]).is_err());
+  }
+
+  #[test]
+  fn reload_keeps_entries_of_unreadable_files() {
+    let path = file("reload", "192.0.2.1\n");
+    let list = NetList::new(vec![], vec![path.clone()]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    list.reload();
+    assert!(list.contains(ip("192.0.2.1")));
+  }
+
+  #[test]
+  fn ignores_forwarded_for_of_untrusted_peers() {
+    let req = request("192.0.2.1:4711", &["198.51.100.1"]);
+    assert_eq!(client_ip(&req, &[net("10.0.0.0/8")]), Some(ip("192.0.2.1")));
+  }
+
+  #[test]
+  fn takes_right_most_untrusted_hop() {
+    let trusted = [net("10.0.0.0/8")];
+
+    let req = request("10.0.0.1:4711", &["198.51.100.1, 192.0.2.1, 10.0.0.2"]);
+    assert_eq!(client_ip(&req, &trusted), Some(ip("192.0.2.1")));
+
+    let req = request("10.0.0.1:4711", &["198.51.100.1", "192.0.2.1, 10.0.0.2"]);
+    assert_eq!(client_ip(&req, &trusted), Some(ip("192.0.2.1")));
+
+    let req = request("10.0.0.1:4711", &["10.0.0.3, 10.0.0.2"]);
+    assert_eq!(client_ip(&req, &trusted), Some(ip("10.0.0.3")));
+
+    let req = request("10.0.0.1:4711", &[]);
+    assert_eq!(client_ip(&req, &trusted), Some(ip("10.0.0.1")));
+  }
+
+  #[test]
+  fn stops_at_invalid_hops() {
+    let req = request("10.0.0.1:4711", &["192.0.2.1, unknown, 10.0.0.2"]);
+    assert_eq!(client_ip(&req, &[net("10.0.0.0/8")]), Some(ip("10.0.0.2")));
+  }
+
+  #[test]
+  fn unmaps_forwarded_ipv4_hops() {
+    let req = request("10.0.0.1:4711", &["::ffff:192.0.2.1, ::ffff:10.0.0.2"]);
+    assert_eq!(client_ip(&req, &[net("10.0.0.0/8")]), Some(ip("192.0.2.1")));
+  }
+}
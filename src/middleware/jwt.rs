@@ -0,0 +1,446 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hyper::header::{HeaderName, AUTHORIZATION, CONNECTION, WWW_AUTHENTICATE};
+use hyper::http::HeaderValue;
+use hyper::{http, Body, HeaderMap, Request, Response, StatusCode, Uri};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::config::JwtConfig;
+use crate::error::PuxError;
+use crate::middleware::{quoted_string, AuthenticatedUser, Middleware, Next};
+use crate::upstream::Upstream;
+use crate::PuxResult;
+
+/// Validates `Authorization: Bearer` tokens and passes selected claims to the backend.
+pub(crate) struct Jwt {
+  realm: String,
+  /// Shared secret for HMAC signed tokens.
+  secret: Option<DecodingKey>,
+  keys: RwLock<Vec<Key>>,
+  /// Allowed signature algorithms.
+  algorithms: Vec<Algorithm>,
+  issuer: Option<String>,
+  audience: Option<String>,
+  /// Seconds of tolerated clock skew for `exp` and `nbf`.
+  leeway: u64,
+  required_claims: Vec<String>,
+  claim_headers: Vec<(String, HeaderName)>,
+  strip_header: bool,
+}
+
+struct Key {
+  id: Option<String>,
+  algorithm: Option<Algorithm>,
+  key: DecodingKey,
+}
+
+pub(crate) enum JwksSource {
+  File(PathBuf),
+  Upstream(Arc<Upstream>, Uri, Duration),
+}
+
+impl Jwt {
+  pub(crate) fn new(conf: &JwtConfig) -> PuxResult<Self> {
+    let algorithms = conf
+      .algorithms
+      .iter()
+      .map(|alg| {
+        Algorithm::from_str(alg)
+          .map_err(|_| PuxError::Config(format!("unsupported jwt algorithm {}", alg)))
+      })
+      .collect::<PuxResult<_>>()?;
+    let claim_headers = conf
+      .claim_headers
+      .iter()
+      .map(|claim_header| {
+        HeaderName::try_from(&claim_header.header)
+          .map(|name| (claim_header.claim.clone(), name))
+          .map_err(|_| {
+            PuxError::Config(format!("invalid claim header name {}", claim_header.header))
+          })
+      })
+      .collect::<PuxResult<_>>()?;
+
+    Ok(Self {
+      realm: conf.realm.clone(),
+      secret: conf
+        .secret
+        .as_ref()
+        .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+      keys: RwLock::new(Vec::new()),
+      algorithms,
+      issuer: conf.issuer.clone(),
+      audience: conf.audience.clone(),
+      leeway: conf.leeway,
+      required_claims: conf.required_claims.clone(),
+      claim_headers,
+      strip_header: conf.strip_header,
+    })
+  }
+
+  /// Loads the key set once from a file or periodically from an upstream.
+  pub(crate) fn load_jwks(self: &Arc<Self>, source: JwksSource) -> PuxResult<()> {
+    match source {
+      JwksSource::File(path) => {
+        let jwks = serde_json::from_str(&std::fs::read_to_string(path)?)
+          .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        self.set_jwks(jwks);
+      }
+      JwksSource::Upstream(upstream, uri, refresh) => {
+        let jwt = self.clone();
+        tokio::spawn(async move {
+          loop {
+            match fetch_jwks(&upstream, &uri).await {
+              Ok(jwks) => jwt.set_jwks(jwks),
+              Err(err) => warn!("Unable to fetch jwks from {}: {}", uri, err),
+            }
+            sleep(refresh).await;
+          }
+        });
+      }
+    }
+
+    Ok(())
+  }
+
+  fn set_jwks(&self, jwks: JwkSet) {
+    let mut keys = Vec::with_capacity(jwks.keys.len());
+    for jwk in &jwks.keys {
+      match DecodingKey::from_jwk(jwk) {
+        Ok(key) => keys.push(Key {
+          id: jwk.common.key_id.clone(),
+          algorithm: jwk
+            .common
+            .key_algorithm
+            .and_then(|alg| Algorithm::from_str(&alg.to_string()).ok()),
+          key,
+        }),
+        Err(err) => warn!("Ignoring unsupported jwk {:?}: {}", jwk.common.key_id, err),
+      }
+    }
+
+    info!("Loaded {} json web keys", keys.len());
+    *self.keys.write().unwrap() = keys;
+  }
+
+  fn validate(&self, token: &str) -> Option<Map<String, Value>> {
+    let header = decode_header(token).ok()?;
+    if !self.algorithms.contains(&header.alg) {
+      debug!("Rejecting token signed with {:?}", header.alg);
+      return None;
+    }
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = self.leeway;
+    validation.validate_nbf = true;
+    if let Some(issuer) = &self.issuer {
+      validation.set_issuer(&[issuer]);
+    }
+    match &self.audience {
+      Some(audience) => validation.set_audience(&[audience]),
+      None => validation.validate_aud = false,
+    }
+
+    let keys = self.keys.read().unwrap();
+    let claims = keys
+      .iter()
+      .filter(|key| header.kid.is_none() || key.id.is_none() || key.id == header.kid)
+      .filter(|key| key.algorithm.is_none_or(|alg| alg == header.alg))
+      .map(|key| &key.key)
+      .chain(&self.secret)
+      .find_map(|key| decode::<Map<String, Value>>(token, key, &validation).ok())?
+      .claims;
+
+    self
+      .required_claims
+      .iter()
+      .all(|claim| claims.contains_key(claim))
+      .then_some(claims)
+  }
+
+  fn challenge(&self, error: Option<&str>) -> PuxResult<PuxError> {
+    let value = match error {
+      Some(error) => format!(
+        "Bearer realm={}, error=\"{}\"",
+        quoted_string(&self.realm),
+        error
+      ),
+      None => format!("Bearer realm={}", quoted_string(&self.realm)),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      WWW_AUTHENTICATE,
+      HeaderValue::try_from(value).map_err(http::Error::from)?,
+    );
+    Ok(PuxError::StatusWithHeaders(
+      StatusCode::UNAUTHORIZED,
+      headers,
+    ))
+  }
+}
+
+#[async_trait]
+impl Middleware for Jwt {
+  async fn handle(&self, mut req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let token = req
+      .headers()
+      .get(AUTHORIZATION)
+      .and_then(|raw| raw.to_str().ok())
+      .and_then(|raw| raw.split_once(' '))
+      .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+      .map(|(_, token)| token.trim());
+
+    let claims = match token {
+      Some(token) => match self.validate(token) {
+        Some(claims) => claims,
+        None => return Err(self.challenge(Some("invalid_token"))?),
+      },
+      None => return Err(self.challenge(None)?),
+    };
+
+    // never pass through client supplied values for the claim headers
+    for (claim, name) in &self.claim_headers {
+      req.headers_mut().remove(name);
+      let value = match claims.get(claim) {
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Null) | None => continue,
+        Some(value) => value.to_string(),
+      };
+      if let Ok(value) = HeaderValue::try_from(value) {
+        req.headers_mut().insert(name.clone(), value);
+      }
+    }
+
+    if let Some(Value::String(sub)) = claims.get("sub") {
+      req.extensions_mut().insert(AuthenticatedUser(sub.clone()));
+    }
+
+    if self.strip_header {
+      req.headers_mut().remove(AUTHORIZATION);
+    }
+
+    next.run(req).await
+  }
+}
+
+async fn fetch_jwks(upstream: &Upstream, uri: &Uri) -> Result<JwkSet, String> {
+  let req = Request::get(uri.clone())
+    .header(CONNECTION, "keep-alive")
+    .body(Body::empty())
+    .map_err(|err| err.to_string())?;
+
+  let resp = upstream.send(req).await.map_err(|err| err.to_string())?;
+  if !resp.status().is_success() {
+    return Err(format!("unexpected status {}", resp.status()));
+  }
+
+  let body = hyper::body::to_bytes(resp.into_body())
+    .await
+    .map_err(|err| err.to_string())?;
+  serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{SystemTime, UNIX_EPOCH};
+
+  use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+  use base64::Engine;
+  use jsonwebtoken::{encode, EncodingKey, Header};
+  use serde_json::json;
+
+  use super::*;
+  use crate::middleware::testing::{route, run};
+
+  fn jwt(extra: &str) -> Jwt {
+    Jwt::new(&serde_yaml::from_str(&format!("{{ id: jwt, {} }}", extra)).unwrap()).unwrap()
+  }
+
+  fn now() -> i64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs() as i64
+  }
+
+  fn token(alg: Algorithm, kid: Option<&str>, secret: &str, claims: Value) -> String {
+    let mut header = Header::new(alg);
+    header.kid = kid.map(str::to_string);
+    encode(
+      &header,
+      &claims,
+      &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+  }
+
+  fn signed(secret: &str, claims: Value) -> String {
+    token(Algorithm::HS256, None, secret, claims)
+  }
+
+  #[test]
+  fn rejects_invalid_config() {
+    let parse = |extra: &str| {
+      Jwt::new(&serde_yaml::from_str(&format!("{{ id: jwt, {} }}", extra)).unwrap()).is_err()
+    };
+    assert!(parse("algorithms: [ HS257 ]"));
+    assert!(parse("claim_headers: [ { claim: sub, header: 'x user' } ]"));
+  }
+
+  #[test]
+  fn checks_signature() {
+    let jwt = jwt("secret: secret");
+    let claims = json!({ "sub": "alice", "exp": now() + 60 });
+    assert!(jwt.validate(&signed("secret", claims.clone())).is_some());
+    assert!(jwt.validate(&signed("other", claims.clone())).is_none());
+
+    let mut tampered = signed("secret", claims);
+    tampered.insert(tampered.rfind('.').unwrap() + 1, 'A');
+    assert!(jwt.validate(&tampered).is_none());
+    assert!(jwt.validate("not.a.token").is_none());
+  }
+
+  #[test]
+  fn checks_algorithm() {
+    let jwt = jwt("secret: secret, algorithms: [ HS384 ]");
+    let claims = json!({ "exp": now() + 60 });
+    let hs256 = token(Algorithm::HS256, None, "secret", claims.clone());
+    let hs384 = token(Algorithm::HS384, None, "secret", claims.clone());
+    assert!(jwt.validate(&hs256).is_none());
+    assert!(jwt.validate(&hs384).is_some());
+
+    let unsigned = format!(
+      "{}.{}.",
+      URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#),
+      URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    assert!(jwt.validate(&unsigned).is_none());
+  }
+
+  #[test]
+  fn checks_expiry() {
+    let jwt = jwt("secret: secret, leeway: 30");
+    let valid = |claims| jwt.validate(&signed("secret", claims)).is_some();
+    assert!(valid(json!({ "exp": now() + 60 })));
+    assert!(valid(json!({ "exp": now() - 10 })));
+    assert!(!valid(json!({ "exp": now() - 60 })));
+    assert!(!valid(json!({ "sub": "alice" })));
+    assert!(valid(json!({ "exp": now() + 60, "nbf": now() + 10 })));
+    assert!(!valid(json!({ "exp": now() + 600, "nbf": now() + 60 })));
+  }
+
+  #[test]
+  fn checks_audience_and_issuer() {
+    let jwt = jwt("secret: secret, audience: api, issuer: https://auth.example.com");
+    let valid = |aud: &str, iss: &str| {
+      let claims = json!({ "exp": now() + 60, "aud": aud, "iss": iss });
+      jwt.validate(&signed("secret", claims)).is_some()
+    };
+    assert!(valid("api", "https://auth.example.com"));
+    assert!(!valid("web", "https://auth.example.com"));
+    assert!(!valid("api", "https://evil.com"));
+
+    // without a configured audience any audience is accepted
+    let jwt = self::jwt("secret: secret");
+    let claims = json!({ "exp": now() + 60, "aud": "web" });
+    assert!(jwt.validate(&signed("secret", claims)).is_some());
+  }
+
+  #[test]
+  fn checks_required_claims() {
+    let jwt = jwt("secret: secret, required_claims: [ email ]");
+    let claims = json!({ "exp": now() + 60 });
+    assert!(jwt.validate(&signed("secret", claims)).is_none());
+    let claims = json!({ "exp": now() + 60, "email": "alice@example.com" });
+    assert!(jwt.validate(&signed("secret", claims)).is_some());
+  }
+
+  #[test]
+  fn selects_jwks_key_by_id() {
+    let jwt = jwt("algorithms: [ HS256 ]");
+    let key = |kid: &str, secret: &str| json!({ "kty": "oct", "kid": kid, "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(secret) });
+    jwt.set_jwks(
+      serde_json::from_value(json!({ "keys": [key("a", "first"), key("b", "second")] })).unwrap(),
+    );
+
+    let claims = json!({ "exp": now() + 60 });
+    let valid = |kid, secret| {
+      jwt
+        .validate(&token(Algorithm::HS256, kid, secret, claims.clone()))
+        .is_some()
+    };
+    assert!(valid(Some("a"), "first"));
+    assert!(valid(Some("b"), "second"));
+    assert!(!valid(Some("a"), "second"));
+    assert!(!valid(Some("c"), "second"));
+    // tokens without a key id are checked against every key
+    assert!(valid(None, "second"));
+  }
+
+  #[tokio::test]
+  async fn passes_claims_to_backend() {
+    let jwt = jwt(
+      "secret: secret, strip_header: true, \
+       claim_headers: [ { claim: sub, header: x-user }, { claim: admin, header: x-admin } ]",
+    );
+    let route = route(|req| {
+      assert!(!req.headers().contains_key(AUTHORIZATION));
+      assert_eq!(req.headers()["x-user"], "alice");
+      assert!(!req.headers().contains_key("x-admin"));
+      assert_eq!(
+        req.extensions().get::<AuthenticatedUser>().unwrap().0,
+        "alice"
+      );
+      Ok(Response::new(Body::empty()))
+    });
+
+    let req = Request::builder()
+      .header(
+        AUTHORIZATION,
+        format!(
+          "Bearer {}",
+          signed("secret", json!({ "sub": "alice", "exp": now() + 60 }))
+        ),
+      )
+      .header("x-admin", "true")
+      .body(Body::empty())
+      .unwrap();
+    assert!(run(&jwt, &route, req).await.is_ok());
+  }
+
+  #[tokio::test]
+  async fn challenges_missing_and_invalid_tokens() {
+    let jwt = jwt("secret: secret");
+    let route = route(|_| Ok(Response::new(Body::empty())));
+    let challenge = |result: PuxResult<Response<Body>>| match result {
+      Err(PuxError::StatusWithHeaders(StatusCode::UNAUTHORIZED, headers)) => {
+        headers[WWW_AUTHENTICATE].clone()
+      }
+      _ => panic!("expected a challenge"),
+    };
+
+    let req = Request::new(Body::empty());
+    assert_eq!(
+      challenge(run(&jwt, &route, req).await),
+      "Bearer realm=\"pux\""
+    );
+
+    let req = Request::builder()
+      .header(AUTHORIZATION, "Bearer invalid")
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(
+      challenge(run(&jwt, &route, req).await),
+      "Bearer realm=\"pux\", error=\"invalid_token\""
+    );
+  }
+}
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+
+use crate::routes::Route;
+use crate::PuxResult;
+
+pub(crate) mod basic_auth;
+pub(crate) mod compression;
+pub(crate) mod concurrency_limit;
+pub(crate) mod cors;
+pub(crate) mod forward_auth;
+pub(crate) mod headers;
+pub(crate) mod ip_filter;
+pub(crate) mod jwt;
+pub(crate) mod rate_limit;
+pub(crate) mod security_headers;
+
+/// The user verified by an authentication middleware, available in the request extensions.
+#[derive(Clone)]
+pub(crate) struct AuthenticatedUser(pub(crate) String);
+
+#[async_trait]
+pub(crate) trait Middleware {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>>;
+}
+
+/// The remaining middlewares of a route followed by its service.
+pub(crate) struct Next<'a> {
+  middlewares: &'a [Arc<dyn Middleware + Send + Sync>],
+  route: &'a Route,
+}
+
+impl<'a> Next<'a> {
+  pub(crate) fn new(
+    middlewares: &'a [Arc<dyn Middleware + Send + Sync>],
+    route: &'a Route,
+  ) -> Self {
+    Self { middlewares, route }
+  }
+
+  pub(crate) fn route(&self) -> &'a Route {
+    self.route
+  }
+
+  pub(crate) async fn run(self, req: Request<Body>) -> PuxResult<Response<Body>> {
+    match self.middlewares.split_first() {
+      Some((middleware, middlewares)) => {
+        let next = Next {
+          middlewares,
+          route: self.route,
+        };
+        middleware.handle(req, next).await
+      }
+      None => self.route.call_service(req).await,
+    }
+  }
+}
+
+/// Quotes an auth parameter like `realm`, escaping quotes and backslashes.
+pub(crate) fn quoted_string(value: &str) -> String {
+  let mut quoted = String::with_capacity(value.len() + 2);
+  quoted.push('"');
+  for char in value.chars() {
+    if char == '"' || char == '\\' {
+      quoted.push('\\');
+    }
+    quoted.push(char);
+  }
+  quoted.push('"');
+  quoted
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+  use async_trait::async_trait;
+  use hyper::{Body, Request, Response};
+
+  use super::{Middleware, Next};
+  use crate::routes::Route;
+  use crate::service::Service;
+  use crate::PuxResult;
+
+  struct Respond<F>(F);
+
+  #[async_trait]
+  impl<F> Service for Respond<F>
+  where
+    F: Fn(Request<Body>) -> PuxResult<Response<Body>> + Send + Sync,
+  {
+    async fn handle(&self, req: Request<Body>) -> PuxResult<Response<Body>> {
+      (self.0)(req)
+    }
+  }
+
+  /// A route without middlewares whose service answers with `respond`.
+  pub(crate) fn route<F>(respond: F) -> Route
+  where
+    F: Fn(Request<Body>) -> PuxResult<Response<Body>> + Send + Sync + 'static,
+  {
+    Route {
+      name: "test".to_string(),
+      matcher: None,
+      rewrite: None,
+      maintenance: None,
+      error_pages: None,
+      intercept_errors: false,
+      middlewares: Vec::new(),
+      service: std::sync::Arc::new(Respond(respond)),
+      service_id: "test".to_string(),
+      middleware_ids: Vec::new(),
+    }
+  }
+
+  /// Passes the request through the middleware to the service of the route.
+  pub(crate) async fn run(
+    middleware: &(dyn Middleware + Send + Sync),
+    route: &Route,
+    req: Request<Body>,
+  ) -> PuxResult<Response<Body>> {
+    middleware.handle(req, Next::new(&[], route)).await
+  }
+}
@@ -0,0 +1,374 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hyper::header::{HeaderName, RETRY_AFTER};
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use ipnet::IpNet;
+use tokio::time::sleep;
+
+use crate::entrypoint::ConnInfo;
+use crate::error::PuxError;
+use crate::middleware::{AuthenticatedUser, Middleware, Next};
+use crate::PuxResult;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Buckets are spread over multiple locks, so concurrent requests rarely wait for each other.
+const SHARDS: usize = 16;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) enum RateLimitKey {
+  /// The client address truncated to the given ipv4 and ipv6 prefix lengths.
+  Ip(u8, u8),
+  /// Falls back to the client address if the header is missing.
+  Header(HeaderName),
+  /// Falls back to the client address for unauthenticated requests.
+  User,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq)]
+enum Key {
+  Ip(IpAddr),
+  Value(String),
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+/// Token bucket per key, refilled with `rate` tokens per second up to `burst`.
+pub(crate) struct RateLimit {
+  key: RateLimitKey,
+  rate: f64,
+  burst: f64,
+  shards: Vec<Mutex<HashMap<Key, Bucket>>>,
+}
+
+enum Decision {
+  Allowed { remaining: f64 },
+  Limited { tokens: f64, retry_after: f64 },
+}
+
+impl RateLimit {
+  /// Fails unless `rate` is positive and `burst` allows at least one request.
+  pub(crate) fn new(key: RateLimitKey, rate: f64, burst: f64) -> PuxResult<Arc<Self>> {
+    if !rate.is_finite() || rate <= 0.0 {
+      return Err(PuxError::Config(format!(
+        "rate limit rate and period must be positive, got {} per second",
+        rate
+      )));
+    }
+    if !burst.is_finite() || burst < 1.0 {
+      return Err(PuxError::Config(format!(
+        "rate limit burst must be at least 1, got {}",
+        burst
+      )));
+    }
+
+    let rate_limit = Arc::new(Self {
+      key,
+      rate,
+      burst,
+      shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+    });
+
+    let weak = Arc::downgrade(&rate_limit);
+    tokio::spawn(async move {
+      loop {
+        sleep(CLEANUP_INTERVAL).await;
+        match weak.upgrade() {
+          Some(rate_limit) => rate_limit.expire_idle(),
+          None => break,
+        }
+      }
+    });
+
+    Ok(rate_limit)
+  }
+
+  fn key(&self, req: &Request<Body>) -> Option<Key> {
+    let ip = |v4: u8, v6: u8| {
+      req.extensions().get::<ConnInfo>().map(|conn_info| {
+        // mapped ipv4 addresses would otherwise share a single ipv6 prefix
+        let ip = conn_info.peer_addr.ip().to_canonical();
+        let prefix = if ip.is_ipv4() { v4 } else { v6 };
+        Key::Ip(IpNet::new(ip, prefix).map_or(ip, |net| net.network()))
+      })
+    };
+
+    match &self.key {
+      RateLimitKey::Ip(v4, v6) => ip(*v4, *v6),
+      RateLimitKey::Header(name) => match req.headers().get(name).map(HeaderValue::to_str) {
+        Some(Ok(value)) => Some(Key::Value(value.to_string())),
+        _ => ip(32, 128),
+      },
+      RateLimitKey::User => match req.extensions().get::<AuthenticatedUser>() {
+        Some(AuthenticatedUser(user)) => Some(Key::Value(user.clone())),
+        None => ip(32, 128),
+      },
+    }
+  }
+
+  fn acquire(&self, key: Key) -> Decision {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let mut buckets = self.shards[hasher.finish() as usize % SHARDS]
+      .lock()
+      .unwrap();
+
+    let now = Instant::now();
+    let bucket = buckets.entry(key).or_insert(Bucket {
+      tokens: self.burst,
+      updated: now,
+    });
+
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Decision::Allowed {
+        remaining: bucket.tokens,
+      }
+    } else {
+      Decision::Limited {
+        tokens: bucket.tokens,
+        retry_after: (1.0 - bucket.tokens) / self.rate,
+      }
+    }
+  }
+
+  /// Removes buckets that would be full again, they behave like new ones.
+  fn expire_idle(&self) {
+    let refill = Duration::from_secs_f64(self.burst / self.rate);
+    for shard in &self.shards {
+      shard
+        .lock()
+        .unwrap()
+        .retain(|_, bucket| bucket.updated.elapsed() < refill);
+    }
+  }
+
+  fn headers(&self, tokens: f64) -> HeaderMap {
+    let reset = (self.burst - tokens) / self.rate;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.burst as u64));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(tokens as u64));
+    headers.insert(RATELIMIT_RESET, HeaderValue::from(reset.ceil() as u64));
+    headers
+  }
+}
+
+#[async_trait]
+impl Middleware for RateLimit {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let key = match self.key(&req) {
+      Some(key) => key,
+      None => return next.run(req).await,
+    };
+
+    match self.acquire(key) {
+      Decision::Allowed { remaining } => match next.run(req).await {
+        Ok(mut resp) => {
+          resp.headers_mut().extend(self.headers(remaining));
+          Ok(resp)
+        }
+        Err(PuxError::Status(code)) => {
+          Err(PuxError::StatusWithHeaders(code, self.headers(remaining)))
+        }
+        Err(PuxError::StatusWithHeaders(code, mut headers)) => {
+          headers.extend(self.headers(remaining));
+          Err(PuxError::StatusWithHeaders(code, headers))
+        }
+        Err(err) => Err(err),
+      },
+      Decision::Limited {
+        tokens,
+        retry_after,
+      } => {
+        let mut headers = self.headers(tokens);
+        headers.insert(RETRY_AFTER, HeaderValue::from(retry_after.ceil() as u64));
+        Err(PuxError::StatusWithHeaders(
+          StatusCode::TOO_MANY_REQUESTS,
+          headers,
+        ))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rate_limit(rate: f64, burst: f64) -> Arc<RateLimit> {
+    RateLimit::new(RateLimitKey::Ip(32, 128), rate, burst).unwrap()
+  }
+
+  fn key() -> Key {
+    Key::Value("client".to_string())
+  }
+
+  /// Pretends the bucket was last used `elapsed` ago.
+  fn rewind(rate_limit: &RateLimit, elapsed: Duration) {
+    for shard in &rate_limit.shards {
+      for bucket in shard.lock().unwrap().values_mut() {
+        bucket.updated -= elapsed;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn rejects_invalid_rates() {
+    let new = |rate, burst| RateLimit::new(RateLimitKey::User, rate, burst).is_err();
+    assert!(new(0.0, 1.0));
+    assert!(new(-1.0, 1.0));
+    assert!(new(f64::NAN, 1.0));
+    // a period of 0 seconds
+    assert!(new(1.0 / 0.0, 1.0));
+    assert!(new(1.0, 0.5));
+    assert!(new(1.0, f64::NAN));
+    assert!(!new(0.001, 1.0));
+  }
+
+  #[tokio::test]
+  async fn allows_burst_then_limits() {
+    let rate_limit = rate_limit(1.0, 3.0);
+    for remaining in [2.0, 1.0, 0.0] {
+      match rate_limit.acquire(key()) {
+        Decision::Allowed { remaining: actual } => assert!((actual - remaining).abs() < 0.01),
+        Decision::Limited { .. } => panic!("limited within the burst"),
+      }
+    }
+
+    match rate_limit.acquire(key()) {
+      Decision::Limited { retry_after, .. } => assert!(retry_after > 0.9 && retry_after <= 1.0),
+      Decision::Allowed { .. } => panic!("allowed beyond the burst"),
+    }
+  }
+
+  #[tokio::test]
+  async fn refills_over_time() {
+    let rate_limit = rate_limit(2.0, 2.0);
+    rate_limit.acquire(key());
+    rate_limit.acquire(key());
+    assert!(matches!(
+      rate_limit.acquire(key()),
+      Decision::Limited { .. }
+    ));
+
+    rewind(&rate_limit, Duration::from_millis(500));
+    assert!(matches!(
+      rate_limit.acquire(key()),
+      Decision::Allowed { .. }
+    ));
+    assert!(matches!(
+      rate_limit.acquire(key()),
+      Decision::Limited { .. }
+    ));
+
+    // never refilled beyond the burst
+    rewind(&rate_limit, Duration::from_secs(60));
+    for _ in 0..2 {
+      assert!(matches!(
+        rate_limit.acquire(key()),
+        Decision::Allowed { .. }
+      ));
+    }
+    assert!(matches!(
+      rate_limit.acquire(key()),
+      Decision::Limited { .. }
+    ));
+  }
+
+  #[tokio::test]
+  async fn keys_have_separate_buckets() {
+    let rate_limit = rate_limit(1.0, 1.0);
+    rate_limit.acquire(key());
+    assert!(matches!(
+      rate_limit.acquire(Key::Value("other".to_string())),
+      Decision::Allowed { .. }
+    ));
+  }
+
+  #[tokio::test]
+  async fn expires_full_buckets() {
+    let rate_limit = rate_limit(0.5, 1.0);
+    rate_limit.acquire(key());
+    rate_limit.expire_idle();
+    assert_eq!(
+      rate_limit
+        .shards
+        .iter()
+        .map(|shard| shard.lock().unwrap().len())
+        .sum::<usize>(),
+      1
+    );
+
+    rewind(&rate_limit, Duration::from_secs(2));
+    rate_limit.expire_idle();
+    assert_eq!(
+      rate_limit
+        .shards
+        .iter()
+        .map(|shard| shard.lock().unwrap().len())
+        .sum::<usize>(),
+      0
+    );
+  }
+
+  #[tokio::test]
+  async fn fractional_rates() {
+    let rate_limit = rate_limit(0.1, 1.0);
+    rate_limit.acquire(key());
+    match rate_limit.acquire(key()) {
+      Decision::Limited {
+        tokens,
+        retry_after,
+      } => {
+        assert_eq!(rate_limit.headers(tokens)[RATELIMIT_RESET], "10");
+        assert!(retry_after > 9.9 && retry_after <= 10.0);
+      }
+      Decision::Allowed { .. } => panic!("allowed beyond the burst"),
+    }
+  }
+
+  #[tokio::test]
+  async fn ipv4_prefix_for_mapped_addresses() {
+    let rate_limit = RateLimit::new(RateLimitKey::Ip(24, 64), 1.0, 1.0).unwrap();
+    let key = |peer_addr: &str| {
+      let mut req = Request::new(Body::empty());
+      req.extensions_mut().insert(ConnInfo {
+        peer_addr: peer_addr.parse().unwrap(),
+        tls: false,
+        tls_version: None,
+        sni: None,
+      });
+      rate_limit.key(&req).unwrap()
+    };
+
+    assert_eq!(
+      key("[::ffff:192.0.2.1]:4711"),
+      Key::Ip("192.0.2.0".parse().unwrap())
+    );
+    assert_eq!(
+      key("[::ffff:198.51.100.1]:4711"),
+      Key::Ip("198.51.100.0".parse().unwrap())
+    );
+    assert_eq!(
+      key("[2001:db8::1]:4711"),
+      Key::Ip("2001:db8::".parse().unwrap())
+    );
+  }
+}
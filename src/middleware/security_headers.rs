@@ -0,0 +1,160 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderName, STRICT_TRANSPORT_SECURITY};
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response};
+
+use crate::entrypoint::ConnInfo;
+use crate::error::PuxError;
+use crate::middleware::{Middleware, Next};
+use crate::PuxResult;
+
+/// Adds a baseline of security related response headers, values set by the backend are kept
+/// unless `override_existing` is set.
+pub(crate) struct SecurityHeaders {
+  /// Only sent over tls, browsers ignore it otherwise.
+  hsts: Option<HeaderValue>,
+  headers: Vec<(HeaderName, HeaderValue)>,
+  override_existing: bool,
+}
+
+impl SecurityHeaders {
+  pub(crate) fn new(
+    hsts: Option<HeaderValue>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    override_existing: bool,
+  ) -> Self {
+    Self {
+      hsts,
+      headers,
+      override_existing,
+    }
+  }
+
+  fn apply(&self, headers: &mut HeaderMap, tls: bool) {
+    let hsts = self
+      .hsts
+      .as_ref()
+      .filter(|_| tls)
+      .map(|value| (&STRICT_TRANSPORT_SECURITY, value));
+
+    for (name, value) in hsts
+      .into_iter()
+      .chain(self.headers.iter().map(|(k, v)| (k, v)))
+    {
+      if self.override_existing || !headers.contains_key(name) {
+        headers.insert(name.clone(), value.clone());
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl Middleware for SecurityHeaders {
+  async fn handle(&self, req: Request<Body>, next: Next<'_>) -> PuxResult<Response<Body>> {
+    let tls = req
+      .extensions()
+      .get::<ConnInfo>()
+      .is_some_and(|conn_info| conn_info.tls);
+
+    match next.run(req).await {
+      Ok(mut resp) => {
+        self.apply(resp.headers_mut(), tls);
+        Ok(resp)
+      }
+      Err(PuxError::Status(code)) => {
+        let mut headers = HeaderMap::new();
+        self.apply(&mut headers, tls);
+        Err(PuxError::StatusWithHeaders(code, headers))
+      }
+      Err(PuxError::StatusWithHeaders(code, mut headers)) => {
+        self.apply(&mut headers, tls);
+        Err(PuxError::StatusWithHeaders(code, headers))
+      }
+      Err(err) => Err(err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::header::X_FRAME_OPTIONS;
+  use hyper::StatusCode;
+
+  use crate::middleware::testing::{route, run};
+
+  fn security_headers(override_existing: bool) -> SecurityHeaders {
+    SecurityHeaders::new(
+      Some(HeaderValue::from_static("max-age=31536000")),
+      vec![(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"))],
+      override_existing,
+    )
+  }
+
+  fn request(tls: bool) -> Request<Body> {
+    let mut req = Request::new(Body::empty());
+    req.extensions_mut().insert(ConnInfo {
+      peer_addr: "192.0.2.1:4711".parse().unwrap(),
+      tls,
+      tls_version: None,
+      sni: None,
+    });
+    req
+  }
+
+  fn backend() -> crate::routes::Route {
+    route(|_| {
+      Ok(
+        Response::builder()
+          .header(X_FRAME_OPTIONS, "SAMEORIGIN")
+          .body(Body::empty())?,
+      )
+    })
+  }
+
+  #[tokio::test]
+  async fn hsts_only_over_tls() {
+    let route = route(|_| Ok(Response::new(Body::empty())));
+    let middleware = security_headers(false);
+
+    let resp = run(&middleware, &route, request(true)).await.unwrap();
+    assert_eq!(
+      resp.headers()[STRICT_TRANSPORT_SECURITY],
+      "max-age=31536000"
+    );
+    assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+
+    let resp = run(&middleware, &route, request(false)).await.unwrap();
+    assert!(!resp.headers().contains_key(STRICT_TRANSPORT_SECURITY));
+    assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+  }
+
+  #[tokio::test]
+  async fn keeps_backend_values() {
+    let resp = run(&security_headers(false), &backend(), request(true))
+      .await
+      .unwrap();
+    assert_eq!(resp.headers()[X_FRAME_OPTIONS], "SAMEORIGIN");
+  }
+
+  #[tokio::test]
+  async fn overrides_backend_values() {
+    let resp = run(&security_headers(true), &backend(), request(true))
+      .await
+      .unwrap();
+    assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+  }
+
+  #[tokio::test]
+  async fn adds_headers_to_errors() {
+    let route = route(|_| Err(PuxError::Status(StatusCode::FORBIDDEN)));
+    match run(&security_headers(false), &route, request(true)).await {
+      Err(PuxError::StatusWithHeaders(code, headers)) => {
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert_eq!(headers[STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+      }
+      _ => panic!("expected an error with headers"),
+    }
+  }
+}
@@ -0,0 +1,239 @@
+use std::fmt::{self, Display, Formatter};
+
+use regex::Regex;
+
+use crate::template;
+
+/// Parameters captured while matching the request path, available in the request extensions.
+#[derive(Clone, Default)]
+pub struct PathParams(Vec<(String, String)>);
+
+pub enum PathPattern {
+  Segments(Vec<Segment>, bool),
+  Regex(Regex),
+}
+
+pub enum Segment {
+  Literal(String),
+  /// `{name}` captures a single segment, `*` matches one without capturing it.
+  Param(Option<String>),
+  /// A trailing `*` matches one or more remaining segments.
+  Rest,
+}
+
+impl PathParams {
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self
+      .0
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Names the values of the parameter segments of the pattern, in the order they appear.
+  pub fn capture(pattern: &PathPattern, values: &[&str]) -> Self {
+    let names = match pattern {
+      PathPattern::Segments(segments, _) => segments.iter().filter_map(|segment| match segment {
+        Segment::Param(name) => Some(name),
+        Segment::Literal(_) | Segment::Rest => None,
+      }),
+      PathPattern::Regex(_) => return Self::default(),
+    };
+
+    Self(
+      names
+        .zip(values)
+        .filter_map(|(name, value)| Some((name.clone()?, value.to_string())))
+        .collect(),
+    )
+  }
+
+  /// Replaces all `{name}` placeholders with the captured values, unknown names are kept.
+  pub fn expand(&self, template: &str) -> String {
+    template::render(template, |name, value| {
+      self.get(name).map(|param| value.push_str(param)).is_some()
+    })
+  }
+}
+
+impl PathPattern {
+  /// Parses a pattern like `/api`, `/static/*` or `/users/{id}`, a pattern that is not exact
+  /// also matches all paths below it.
+  pub fn parse(pattern: &str, exact: bool) -> Self {
+    let mut segments = split(pattern)
+      .map(|segment| match segment {
+        "*" => Segment::Param(None),
+        _ if segment.starts_with('{') && segment.ends_with('}') => {
+          Segment::Param(Some(segment[1..segment.len() - 1].to_string()))
+        }
+        _ => Segment::Literal(segment.to_string()),
+      })
+      .collect::<Vec<_>>();
+
+    if let Some(Segment::Param(None)) = segments.last() {
+      segments.pop();
+      segments.push(Segment::Rest);
+    }
+
+    Self::Segments(segments, exact)
+  }
+
+  pub fn matches(&self, path: &str) -> Option<PathParams> {
+    match self {
+      Self::Segments(segments, exact) => {
+        let mut params = PathParams::default();
+        let mut supplied = split(path);
+
+        for segment in segments {
+          match (segment, supplied.next()) {
+            (Segment::Literal(literal), Some(value)) if literal == value => {}
+            (Segment::Param(name), Some(value)) => {
+              if let Some(name) = name {
+                params.0.push((name.clone(), value.to_string()));
+              }
+            }
+            (Segment::Rest, Some(_)) => return Some(params),
+            _ => return None,
+          }
+        }
+
+        match (exact, supplied.next()) {
+          (true, Some(_)) => None,
+          _ => Some(params),
+        }
+      }
+      Self::Regex(regex) => {
+        let captures = regex.captures(path)?;
+        let params = regex
+          .capture_names()
+          .flatten()
+          .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+          .collect();
+        Some(PathParams(params))
+      }
+    }
+  }
+}
+
+impl Display for PathPattern {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Segments(segments, _) if segments.is_empty() => write!(f, "/"),
+      Self::Segments(segments, _) => {
+        for segment in segments {
+          match segment {
+            Segment::Literal(literal) => write!(f, "/{}", literal)?,
+            Segment::Param(Some(name)) => write!(f, "/{{{}}}", name)?,
+            Segment::Param(None) | Segment::Rest => write!(f, "/*")?,
+          }
+        }
+        Ok(())
+      }
+      Self::Regex(regex) => write!(f, "~{}", regex),
+    }
+  }
+}
+
+/// Splits a path into its segments, ignoring the leading slash.
+fn split(path: &str) -> impl Iterator<Item = &str> {
+  let path = path.strip_prefix('/').unwrap_or(path);
+  path.split('/').filter(move |_| !path.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn params(pattern: &PathPattern, path: &str) -> Option<Vec<(String, String)>> {
+    pattern.matches(path).map(|params| params.0)
+  }
+
+  fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+      .iter()
+      .map(|(name, value)| (name.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn prefix() {
+    let pattern = PathPattern::parse("/api", false);
+    assert!(pattern.matches("/api").is_some());
+    assert!(pattern.matches("/api/users").is_some());
+    assert!(pattern.matches("/apix").is_none());
+    assert!(pattern.matches("/").is_none());
+
+    let root = PathPattern::parse("/", false);
+    assert!(root.matches("/").is_some());
+    assert!(root.matches("/anything/below").is_some());
+  }
+
+  #[test]
+  fn exact() {
+    let pattern = PathPattern::parse("/api/health", true);
+    assert!(pattern.matches("/api/health").is_some());
+    assert!(pattern.matches("/api/health/deep").is_none());
+    assert!(pattern.matches("/api").is_none());
+  }
+
+  #[test]
+  fn glob() {
+    let pattern = PathPattern::parse("/static/*/app.js", true);
+    assert!(pattern.matches("/static/v1/app.js").is_some());
+    assert!(pattern.matches("/static/app.js").is_none());
+
+    let rest = PathPattern::parse("/static/*", false);
+    assert!(rest.matches("/static/css/main.css").is_some());
+    // the trailing wildcard needs at least one segment
+    assert!(rest.matches("/static").is_none());
+  }
+
+  #[test]
+  fn parameters() {
+    let pattern = PathPattern::parse("/users/{id}/posts/{post}", false);
+    assert_eq!(
+      params(&pattern, "/users/42/posts/7/comments"),
+      Some(pairs(&[("id", "42"), ("post", "7")]))
+    );
+    assert_eq!(params(&pattern, "/users/42"), None);
+  }
+
+  #[test]
+  fn regex() {
+    let pattern = PathPattern::Regex(Regex::new("^/v(?P<version>[0-9]+)/(?P<rest>.*)$").unwrap());
+    assert_eq!(
+      params(&pattern, "/v2/users"),
+      Some(pairs(&[("version", "2"), ("rest", "users")]))
+    );
+    assert_eq!(params(&pattern, "/vx/users"), None);
+  }
+
+  #[test]
+  fn display() {
+    for (raw, exact) in [("/", false), ("/api/{id}/*", false), ("/a/*/b", true)] {
+      assert_eq!(PathPattern::parse(raw, exact).to_string(), raw);
+    }
+    assert_eq!(
+      PathPattern::Regex(Regex::new("^/v1").unwrap()).to_string(),
+      "~^/v1"
+    );
+  }
+
+  #[test]
+  fn capture() {
+    let pattern = PathPattern::parse("/{org}/*/{repo}", false);
+    let params = PathParams::capture(&pattern, &["pux", "skipped", "core"]);
+    assert_eq!(params.0, pairs(&[("org", "pux"), ("repo", "core")]));
+  }
+
+  #[test]
+  fn expand() {
+    let params = PathParams(pairs(&[("id", "42"), ("host", "{id}")]));
+    assert_eq!(
+      params.expand("/users/{id}/{unknown}"),
+      "/users/42/{unknown}"
+    );
+    // values are not expanded again
+    assert_eq!(params.expand("/{host}"), "/{id}");
+  }
+}
@@ -0,0 +1,170 @@
+use std::borrow::Cow;
+
+use hyper::http::uri::PathAndQuery;
+use hyper::{http, Body, Request, Uri};
+use regex::Regex;
+
+use crate::error::PuxResult;
+use crate::path::PathParams;
+use crate::template;
+
+pub(crate) struct Rewrite {
+  strip_prefix: Option<String>,
+  add_prefix: Option<String>,
+  regex: Option<(Regex, String)>,
+}
+
+impl Rewrite {
+  pub(crate) fn new(
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    regex: Option<(Regex, String)>,
+  ) -> Self {
+    Self {
+      strip_prefix: strip_prefix.map(|prefix| prefix.trim_end_matches('/').to_string()),
+      add_prefix: add_prefix.map(|prefix| prefix.trim_end_matches('/').to_string()),
+      regex,
+    }
+  }
+
+  /// Rewrites the path of the request in the order strip prefix, regex, add prefix.
+  /// The query string is left untouched, `{name}` placeholders in the replacement and the added
+  /// prefix are filled with the parameters captured by the route path.
+  pub(crate) fn apply(&self, req: &mut Request<Body>) -> PuxResult<()> {
+    let params = req.extensions().get::<PathParams>();
+    let path = self.rewrite_path(req.uri().path(), params);
+
+    let path_and_query = match req.uri().query() {
+      Some(query) => format!("{}?{}", path, query),
+      None => path.into_owned(),
+    };
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).map_err(http::Error::from)?);
+    *req.uri_mut() = Uri::from_parts(parts).map_err(http::Error::from)?;
+
+    Ok(())
+  }
+
+  fn rewrite_path<'a>(&self, path: &'a str, params: Option<&PathParams>) -> Cow<'a, str> {
+    let mut path = Cow::Borrowed(path);
+
+    if let Some(prefix) = &self.strip_prefix {
+      if *path == *prefix {
+        path = Cow::Borrowed("/");
+      } else if path.starts_with(prefix.as_str()) && path[prefix.len()..].starts_with('/') {
+        path = Cow::Owned(path[prefix.len()..].to_string());
+      }
+    }
+
+    if let Some((regex, replacement)) = &self.regex {
+      let replacement = match params {
+        // captured values are literal text, `$` would otherwise refer to a capture group
+        Some(params) if replacement.contains('{') => {
+          Cow::Owned(template::render(replacement, |name, value| {
+            params
+              .get(name)
+              .map(|param| value.push_str(&param.replace('$', "$$")))
+              .is_some()
+          }))
+        }
+        _ => Cow::Borrowed(replacement.as_str()),
+      };
+      let rewritten = regex.replace(&path, replacement.as_ref());
+      if let Cow::Owned(rewritten) = rewritten {
+        path = Cow::Owned(rewritten);
+      }
+    }
+
+    if let Some(prefix) = &self.add_prefix {
+      path = Cow::Owned(format!("{}{}", expand(prefix, params), path));
+    }
+
+    if !path.starts_with('/') {
+      path = Cow::Owned(format!("/{}", path));
+    }
+
+    path
+  }
+}
+
+fn expand<'a>(template: &'a str, params: Option<&PathParams>) -> Cow<'a, str> {
+  match params {
+    Some(params) if template.contains('{') => Cow::Owned(params.expand(template)),
+    _ => Cow::Borrowed(template),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::path::PathPattern;
+
+  fn rewrite(
+    strip_prefix: Option<&str>,
+    add_prefix: Option<&str>,
+    regex: Option<(&str, &str)>,
+  ) -> Rewrite {
+    Rewrite::new(
+      strip_prefix.map(str::to_string),
+      add_prefix.map(str::to_string),
+      regex.map(|(regex, replacement)| (Regex::new(regex).unwrap(), replacement.to_string())),
+    )
+  }
+
+  fn apply(rewrite: &Rewrite, pattern: &str, uri: &str) -> String {
+    let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    if let Some(params) = PathPattern::parse(pattern, false).matches(req.uri().path()) {
+      req.extensions_mut().insert(params);
+    }
+    rewrite.apply(&mut req).unwrap();
+    req.uri().to_string()
+  }
+
+  #[test]
+  fn strips_prefix() {
+    let rewrite = rewrite(Some("/api/"), None, None);
+    assert_eq!(apply(&rewrite, "/", "/api/users?page=2"), "/users?page=2");
+    assert_eq!(apply(&rewrite, "/", "/api"), "/");
+    assert_eq!(apply(&rewrite, "/", "/apis/users"), "/apis/users");
+  }
+
+  #[test]
+  fn adds_prefix() {
+    let rewrite = rewrite(Some("/api"), Some("/v2/{tenant}/"), None);
+    assert_eq!(
+      apply(&rewrite, "/api/{tenant}", "/api/acme/users"),
+      "/v2/acme/acme/users"
+    );
+    assert_eq!(apply(&rewrite, "/", "/api/users"), "/v2/{tenant}/users");
+  }
+
+  #[test]
+  fn replaces_regex() {
+    let rewrite = rewrite(None, None, Some(("^/old/(.*)$", "/new/$1")));
+    assert_eq!(apply(&rewrite, "/", "/old/a/b?q=1"), "/new/a/b?q=1");
+    assert_eq!(apply(&rewrite, "/", "/other"), "/other");
+  }
+
+  #[test]
+  fn keeps_leading_slash() {
+    let rewrite = rewrite(None, None, Some(("^/static", "")));
+    assert_eq!(apply(&rewrite, "/", "/static"), "/");
+    assert_eq!(apply(&rewrite, "/", "/static/app.js"), "/app.js");
+  }
+
+  #[test]
+  fn expands_placeholders_in_replacement() {
+    let rewrite = rewrite(None, None, Some(("^/users/[^/]+", "/accounts/{id}")));
+    assert_eq!(
+      apply(&rewrite, "/users/{id}", "/users/42/posts"),
+      "/accounts/42/posts"
+    );
+    assert_eq!(apply(&rewrite, "/users/{id}", "/users/$1"), "/accounts/$1");
+    assert_eq!(
+      apply(&rewrite, "/users/{id}", "/users/$$x"),
+      "/accounts/$$x"
+    );
+    assert_eq!(apply(&rewrite, "/other/*", "/users/7"), "/accounts/{id}");
+  }
+}
@@ -0,0 +1,522 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::path::{PathParams, PathPattern, Segment};
+
+/// Lookup structure mapping host and path to a value, built once from all routes of an
+/// entrypoint. Every host gets a tree per priority, whose edges are labeled with one or more
+/// literal path segments, so a lookup only walks the segments of the requested path.
+pub struct Router<T> {
+  hosts: HashMap<String, Tree<T>>,
+}
+
+pub struct RouterBuilder<T> {
+  hosts: HashMap<String, Vec<Entry<T>>>,
+}
+
+struct Entry<T> {
+  priority: i32,
+  path: PathPattern,
+  value: T,
+}
+
+struct Tree<T> {
+  entries: Vec<Entry<T>>,
+  /// Ordered by descending priority.
+  levels: Vec<Level>,
+}
+
+struct Level {
+  priority: i32,
+  root: Node,
+  regex: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Node {
+  /// Sorted by the first segment of the label.
+  literals: Vec<(Vec<String>, Node)>,
+  param: Option<Box<Node>>,
+  rest: Vec<usize>,
+  exact: Vec<usize>,
+  prefix: Vec<usize>,
+}
+
+impl<T> RouterBuilder<T> {
+  pub fn new() -> Self {
+    Self {
+      hosts: HashMap::new(),
+    }
+  }
+
+  pub fn insert(&mut self, host: String, priority: i32, path: PathPattern, value: T) {
+    self.hosts.entry(host).or_default().push(Entry {
+      priority,
+      path,
+      value,
+    });
+  }
+
+  pub fn build(self) -> Router<T> {
+    let hosts = self
+      .hosts
+      .into_iter()
+      .map(|(host, entries)| (host, Tree::new(entries)))
+      .collect();
+
+    Router { hosts }
+  }
+}
+
+impl<T> Default for RouterBuilder<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> Router<T> {
+  /// Returns the first value whose path matches and that is accepted by `filter`. Values are
+  /// tried by descending priority, then literal segments before parameters before trailing
+  /// wildcards, deeper before shallower, exact before prefix and regular expressions last. Catch-all
+  /// routes like `/` or `/*` come after the regular expressions of their priority, they would
+  /// otherwise hide them.
+  pub fn find(
+    &self,
+    host: &str,
+    path: &str,
+    filter: impl Fn(&T) -> bool,
+  ) -> Option<(&T, PathParams)> {
+    let tree = self.hosts.get(host)?;
+    let check = |i: &usize| filter(&tree.entries[*i].value);
+    // values of the parameter segments on the way to the match, only allocates if there are any
+    let mut values = Vec::new();
+
+    for level in &tree.levels {
+      values.clear();
+      let found = find_in(&level.root, first_segment(path), &check, &mut values);
+      if let Some(i) = found.filter(|i| !level.root.is_catch_all(i)) {
+        let entry = &tree.entries[i];
+        return Some((&entry.value, PathParams::capture(&entry.path, &values)));
+      }
+
+      for i in &level.regex {
+        let entry = &tree.entries[*i];
+        if let Some(params) = entry.path.matches(path) {
+          if check(i) {
+            return Some((&entry.value, params));
+          }
+        }
+      }
+
+      if let Some(i) = found {
+        let entry = &tree.entries[i];
+        return Some((&entry.value, PathParams::capture(&entry.path, &values)));
+      }
+    }
+
+    None
+  }
+}
+
+impl<T> Router<T> {
+  /// All values with their host, priority and path, by host and descending priority.
+  pub fn entries(&self) -> Vec<(&str, i32, &PathPattern, &T)> {
+    let mut entries = self
+      .hosts
+      .iter()
+      .flat_map(|(host, tree)| {
+        tree
+          .entries
+          .iter()
+          .map(move |entry| (host.as_str(), entry.priority, &entry.path, &entry.value))
+      })
+      .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0).then(b.1.cmp(&a.1)));
+    entries
+  }
+}
+
+impl<T> Tree<T> {
+  fn new(entries: Vec<Entry<T>>) -> Self {
+    let mut levels: Vec<Level> = Vec::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+      let level = match levels
+        .iter()
+        .position(|level| level.priority == entry.priority)
+      {
+        Some(level) => &mut levels[level],
+        None => {
+          levels.push(Level {
+            priority: entry.priority,
+            root: Node::default(),
+            regex: Vec::new(),
+          });
+          levels.last_mut().unwrap()
+        }
+      };
+
+      match &entry.path {
+        PathPattern::Segments(segments, exact) => {
+          let mut node = &mut level.root;
+          for segment in segments {
+            node = match segment {
+              Segment::Literal(literal) => node.literal(literal),
+              Segment::Param(_) => node.param.get_or_insert_with(Default::default),
+              Segment::Rest => break,
+            };
+          }
+
+          match (segments.last(), exact) {
+            (Some(Segment::Rest), _) => node.rest.push(i),
+            (_, true) => node.exact.push(i),
+            (_, false) => node.prefix.push(i),
+          }
+        }
+        PathPattern::Regex(_) => level.regex.push(i),
+      }
+    }
+
+    levels.sort_by_key(|level| Reverse(level.priority));
+    for level in &mut levels {
+      level.root.compress();
+    }
+
+    Self { entries, levels }
+  }
+}
+
+impl Node {
+  fn literal(&mut self, segment: &str) -> &mut Node {
+    let index = match self
+      .literals
+      .binary_search_by(|(label, _)| label[0].as_str().cmp(segment))
+    {
+      Ok(index) => index,
+      Err(index) => {
+        self
+          .literals
+          .insert(index, (vec![segment.to_string()], Node::default()));
+        index
+      }
+    };
+
+    &mut self.literals[index].1
+  }
+
+  /// Prefix or wildcard routes of the root, matching every path.
+  fn is_catch_all(&self, i: &usize) -> bool {
+    self.prefix.contains(i) || self.rest.contains(i)
+  }
+
+  fn is_passthrough(&self) -> bool {
+    self.literals.len() == 1
+      && self.param.is_none()
+      && self.rest.is_empty()
+      && self.exact.is_empty()
+      && self.prefix.is_empty()
+  }
+
+  /// Merges chains of nodes that only have a single literal child into one edge.
+  fn compress(&mut self) {
+    for (label, child) in &mut self.literals {
+      while child.is_passthrough() {
+        let (grandchild_label, grandchild) = child.literals.pop().unwrap();
+        label.extend(grandchild_label);
+        *child = grandchild;
+      }
+      child.compress();
+    }
+
+    if let Some(param) = &mut self.param {
+      param.compress();
+    }
+  }
+}
+
+fn find_in<'a>(
+  node: &Node,
+  segment: Option<&'a str>,
+  check: &impl Fn(&usize) -> bool,
+  values: &mut Vec<&'a str>,
+) -> Option<usize> {
+  match segment {
+    Some(remaining) => {
+      let (segment, next) = split_segment(remaining);
+
+      if let Ok(index) = node
+        .literals
+        .binary_search_by(|(label, _)| label[0].as_str().cmp(segment))
+      {
+        let (label, child) = &node.literals[index];
+        if let Some(next) = consume(&label[1..], next) {
+          if let Some(found) = find_in(child, next, check, values) {
+            return Some(found);
+          }
+        }
+      }
+
+      if let Some(child) = &node.param {
+        values.push(segment);
+        if let Some(found) = find_in(child, next, check, values) {
+          return Some(found);
+        }
+        values.pop();
+      }
+
+      if let Some(found) = node.rest.iter().find(|i| check(i)) {
+        return Some(*found);
+      }
+    }
+    None => {
+      if let Some(found) = node.exact.iter().find(|i| check(i)) {
+        return Some(*found);
+      }
+    }
+  }
+
+  node.prefix.iter().find(|i| check(i)).copied()
+}
+
+/// Consumes the given literal segments, returning the remaining path if all of them matched.
+fn consume<'a>(labels: &[String], mut remaining: Option<&'a str>) -> Option<Option<&'a str>> {
+  for label in labels {
+    let (segment, next) = split_segment(remaining?);
+    if segment != label {
+      return None;
+    }
+    remaining = next;
+  }
+
+  Some(remaining)
+}
+
+/// Strips the leading slash, `None` if the path has no segments.
+fn first_segment(path: &str) -> Option<&str> {
+  let path = path.strip_prefix('/').unwrap_or(path);
+  (!path.is_empty()).then_some(path)
+}
+
+fn split_segment(remaining: &str) -> (&str, Option<&str>) {
+  match remaining.split_once('/') {
+    Some((segment, next)) => (segment, Some(next)),
+    None => (remaining, None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use regex::Regex;
+
+  use super::*;
+
+  const HOST: &str = "example.com";
+
+  fn router(routes: &[(i32, &str, bool)]) -> Router<usize> {
+    let mut builder = RouterBuilder::new();
+    for (i, (priority, path, exact)) in routes.iter().enumerate() {
+      builder.insert(
+        HOST.to_string(),
+        *priority,
+        PathPattern::parse(path, *exact),
+        i,
+      );
+    }
+    builder.build()
+  }
+
+  fn find(router: &Router<usize>, path: &str) -> Option<usize> {
+    router.find(HOST, path, |_| true).map(|(value, _)| *value)
+  }
+
+  /// The lookup the tree replaced: literal prefixes scanned by ascending length.
+  fn former(routes: &[(i32, &str, bool)], path: &str) -> Option<usize> {
+    // `/` was the single empty segment
+    let split = |path: &str| {
+      let path = path.trim_end_matches('/');
+      path.split('/').map(str::to_string).collect::<Vec<_>>()
+    };
+    let supplied = split(path);
+    let mut paths: Vec<_> = routes
+      .iter()
+      .enumerate()
+      .map(|(i, (_, path, _))| (split(path), i))
+      .collect();
+    paths.sort_by_key(|(path, _)| path.len());
+    paths
+      .into_iter()
+      .find(|(path, _)| supplied.starts_with(path))
+      .map(|(_, i)| i)
+  }
+
+  /// Every literal prefix route over a small alphabet, checked against a linear scan preferring the
+  /// highest priority, then the deepest route.
+  #[test]
+  fn agrees_with_linear_lookup() {
+    let mut patterns = vec!["/".to_string()];
+    let mut parents = vec![String::new()];
+    for _ in 0..3 {
+      let children: Vec<_> = parents
+        .iter()
+        .flat_map(|parent| ["a", "b", "c"].map(|segment| format!("{}/{}", parent, segment)))
+        .collect();
+      patterns.extend(children.iter().cloned());
+      parents = children;
+    }
+    // drop some routes, so lookups have to fall back to shallower ones
+    let routes: Vec<(i32, &str, bool)> = patterns
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| i % 3 != 1)
+      .map(|(i, pattern)| ((i % 4 == 0) as i32, pattern.as_str(), false))
+      .collect();
+    let router = router(&routes);
+
+    let linear = |path: &str, accept: &dyn Fn(usize) -> bool| {
+      routes
+        .iter()
+        .enumerate()
+        .filter(|(i, (_, pattern, exact))| {
+          accept(*i) && PathPattern::parse(pattern, *exact).matches(path).is_some()
+        })
+        .max_by_key(|(i, (priority, pattern, _))| {
+          (*priority, pattern.trim_end_matches('/').len(), Reverse(*i))
+        })
+        .map(|(i, _)| i)
+    };
+
+    for pattern in &patterns {
+      for path in [
+        pattern.clone(),
+        format!("{}/x/y", pattern.trim_end_matches('/')),
+      ] {
+        assert_eq!(find(&router, &path), linear(&path, &|_| true), "{}", path);
+
+        let odd = |i: usize| i % 2 == 1;
+        let found = router
+          .find(HOST, &path, |i| odd(*i))
+          .map(|(value, _)| *value);
+        assert_eq!(found, linear(&path, &odd), "{} filtered", path);
+      }
+    }
+  }
+
+  #[test]
+  fn agrees_with_former_lookup_without_overlaps() {
+    let routes = [
+      (0, "/api", false),
+      (0, "/static/css", false),
+      (0, "/", false),
+    ];
+    let router = router(&routes[..2]);
+    for path in [
+      "/api",
+      "/api/users",
+      "/static/css/main.css",
+      "/static",
+      "/other",
+    ] {
+      assert_eq!(find(&router, path), former(&routes[..2], path), "{}", path);
+    }
+
+    // overlapping prefixes used to resolve to the shortest one, now the deepest one wins
+    let router = self::router(&routes);
+    assert_eq!(former(&routes, "/api/users"), Some(2));
+    assert_eq!(find(&router, "/api/users"), Some(0));
+    assert_eq!(find(&router, "/other"), Some(2));
+  }
+
+  #[test]
+  fn precedence() {
+    let router = router(&[
+      (0, "/users/*", false),
+      (0, "/users/{id}", false),
+      (0, "/users/me", false),
+      (0, "/users/me/settings", true),
+      (0, "/users", true),
+      (0, "/users", false),
+    ]);
+
+    assert_eq!(find(&router, "/users/me"), Some(2));
+    assert_eq!(find(&router, "/users/me/settings"), Some(3));
+    // the exact route does not match deeper paths, the prefix one does
+    assert_eq!(find(&router, "/users/me/settings/x"), Some(2));
+    assert_eq!(find(&router, "/users/42"), Some(1));
+    assert_eq!(find(&router, "/users"), Some(4));
+    assert_eq!(find(&router, "/users/"), Some(1));
+  }
+
+  #[test]
+  fn parameters_after_failed_literals() {
+    let router = router(&[
+      (0, "/users/me/settings", true),
+      (0, "/users/{id}/avatar", true),
+    ]);
+    let (value, params) = router.find(HOST, "/users/me/avatar", |_| true).unwrap();
+    assert_eq!(*value, 1);
+    assert_eq!(params.get("id"), Some("me"));
+  }
+
+  #[test]
+  fn rest_matches_remaining_segments() {
+    let router = router(&[(0, "/static/*", false), (0, "/static/{file}", true)]);
+    assert_eq!(find(&router, "/static/app.js"), Some(1));
+    assert_eq!(find(&router, "/static/css/app.css"), Some(0));
+    assert_eq!(find(&router, "/static"), None);
+  }
+
+  #[test]
+  fn regex_within_priority() {
+    let mut builder = RouterBuilder::new();
+    let regex = |raw| PathPattern::Regex(Regex::new(raw).unwrap());
+    builder.insert(HOST.to_string(), 0, PathPattern::parse("/", false), "root");
+    builder.insert(HOST.to_string(), 0, regex("^/v(?P<v>[0-9]+)/"), "versioned");
+    builder.insert(HOST.to_string(), 1, regex("^/admin"), "admin");
+    builder.insert(
+      HOST.to_string(),
+      0,
+      PathPattern::parse("/v1/health", true),
+      "health",
+    );
+    builder.insert(
+      HOST.to_string(),
+      0,
+      PathPattern::parse("/admin", false),
+      "literal",
+    );
+    let router = builder.build();
+
+    let find = |path| router.find(HOST, path, |_| true).map(|(value, _)| *value);
+    // regular expressions come after the tree of the same priority
+    assert_eq!(find("/v1/health"), Some("health"));
+    // but before its catch-all and lower priorities
+    assert_eq!(find("/v1/users"), Some("versioned"));
+    assert_eq!(find("/users"), Some("root"));
+    assert_eq!(find("/admin/users"), Some("admin"));
+
+    let (value, params) = router.find(HOST, "/v1/users", |_| true).unwrap();
+    assert_eq!(*value, "versioned");
+    assert_eq!(params.get("v"), Some("1"));
+
+    // the catch-all still applies if the expression is filtered out
+    let found = router.find(HOST, "/v1/users", |value| *value != "versioned");
+    assert_eq!(found.map(|(value, _)| *value), Some("root"));
+  }
+
+  #[test]
+  fn unknown_host() {
+    let router = router(&[(0, "/", false)]);
+    assert!(router.find("other.com", "/", |_| true).is_none());
+  }
+
+  #[test]
+  fn compresses_literal_chains() {
+    let router = router(&[(0, "/a/b/c/d", false), (0, "/a/b/x", false)]);
+    let root = &router.hosts[HOST].levels[0].root;
+    assert_eq!(root.literals.len(), 1);
+    assert_eq!(root.literals[0].0, ["a", "b"]);
+    assert_eq!(find(&router, "/a/b/c/d/e"), Some(0));
+    assert_eq!(find(&router, "/a/b/c"), None);
+    assert_eq!(find(&router, "/a/b/x"), Some(1));
+  }
+}
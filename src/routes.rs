@@ -1,56 +1,91 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
 use std::sync::Arc;
 
+use hyper::{Body, Request, Response};
+
+use crate::error::PuxResult;
+use crate::error_page::ErrorPages;
+use crate::maintenance::Maintenance;
+use crate::matcher::Matcher;
+use crate::middleware::{Middleware, Next};
+use crate::path::{PathParams, PathPattern};
+use crate::rewrite::Rewrite;
+use crate::router::{Router, RouterBuilder};
 use crate::service::Service as SService;
 
 pub(crate) type Service = Arc<dyn SService + Send + Sync>;
-pub(crate) type Path = Vec<String>;
-type Route = (Path, Service);
 
-pub(crate) struct Routes(HashMap<String, Vec<Route>>);
+pub(crate) struct Route {
+  pub(crate) name: String,
+  pub(crate) matcher: Option<Matcher>,
+  pub(crate) rewrite: Option<Rewrite>,
+  pub(crate) maintenance: Option<Arc<Maintenance>>,
+  pub(crate) error_pages: Option<Arc<ErrorPages>>,
+  /// Replace server errors returned by the service with our error pages.
+  pub(crate) intercept_errors: bool,
+  pub(crate) middlewares: Vec<Arc<dyn Middleware + Send + Sync>>,
+  pub(crate) service: Service,
+  /// Ids of the configured service and middlewares, for inspection.
+  pub(crate) service_id: String,
+  pub(crate) middleware_ids: Vec<String>,
+}
 
-impl Routes {
-  pub(crate) fn new() -> Self {
-    Self(Default::default())
-  }
+pub(crate) struct Routes(Router<Route>);
 
-  pub(crate) fn insert(&mut self, host: String, path: Path, service: Service) {
-    match self.0.entry(host) {
-      Entry::Occupied(mut occupied) => {
-        let paths = occupied.get_mut();
-        paths.push((path, service));
-        paths.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()))
-      }
-      Entry::Vacant(vacant) => {
-        vacant.insert(vec![(path, service)]);
+pub(crate) struct RoutesBuilder(RouterBuilder<Route>);
+
+impl Route {
+  pub(crate) async fn handle(&self, req: Request<Body>) -> PuxResult<Response<Body>> {
+    if let Some(maintenance) = &self.maintenance {
+      if maintenance.is_enabled() {
+        return maintenance.handle(req).await;
       }
-    };
-  }
+    }
 
-  pub(crate) fn find(&self, supplied_host: &str, supplied_path: &[&str]) -> Option<&Service> {
-    let paths = self.0.get(supplied_host)?;
+    Next::new(&self.middlewares, self).run(req).await
+  }
 
-    for (path, service) in paths {
-      if starts_with(path, supplied_path) {
-        return Some(service);
-      }
+  /// Called after all middlewares, the rewrite only affects the request passed to the service.
+  pub(crate) async fn call_service(&self, mut req: Request<Body>) -> PuxResult<Response<Body>> {
+    if let Some(rewrite) = &self.rewrite {
+      rewrite.apply(&mut req)?;
     }
 
-    None
+    self.service.handle(req).await
   }
 }
 
-fn starts_with(base: &[String], supplied: &[&str]) -> bool {
-  if base.len() > supplied.len() {
-    return false;
+impl RoutesBuilder {
+  pub(crate) fn new() -> Self {
+    Self(RouterBuilder::new())
   }
 
-  for (i, segment) in base.iter().enumerate() {
-    if supplied[i] != segment {
-      return false;
-    }
+  /// Routes with a higher priority are tried first.
+  pub(crate) fn insert(&mut self, host: String, priority: i32, path: PathPattern, route: Route) {
+    self.0.insert(host, priority, path, route);
+  }
+
+  pub(crate) fn build(self) -> Routes {
+    Routes(self.0.build())
   }
+}
 
-  true
+impl Routes {
+  /// Returns the most specific route for the host and path whose matcher accepts the request.
+  pub(crate) fn find(
+    &self,
+    supplied_host: &str,
+    supplied_path: &str,
+    req: &Request<Body>,
+  ) -> Option<(&Route, PathParams)> {
+    self.0.find(supplied_host, supplied_path, |route| {
+      route
+        .matcher
+        .as_ref()
+        .is_none_or(|matcher| matcher.matches(req))
+    })
+  }
+
+  pub(crate) fn entries(&self) -> Vec<(&str, i32, &PathPattern, &Route)> {
+    self.0.entries()
+  }
 }
@@ -4,6 +4,8 @@ use hyper::{Body, Request, Response};
 use crate::PuxResult;
 
 pub(crate) mod proxy;
+pub(crate) mod redirect;
+pub(crate) mod respond;
 
 #[async_trait]
 pub(crate) trait Service {
@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use hyper::header::LOCATION;
+use hyper::http::HeaderValue;
+use hyper::{http, Body, Request, Response, StatusCode};
+
+use crate::entrypoint::ConnInfo;
+use crate::handler::request_host;
+use crate::service::Service;
+use crate::PuxResult;
+
+enum Part {
+  Literal(String),
+  Scheme,
+  Host,
+  Path,
+  Query,
+}
+
+pub(crate) struct RedirectService {
+  location: Vec<Part>,
+  code: StatusCode,
+  preserve_path: bool,
+}
+
+impl RedirectService {
+  /// The location template supports the placeholders `{scheme}`, `{host}`, `{path}` and `{query}`,
+  /// where `{query}` expands to the query including the leading `?` or to nothing.
+  pub(crate) fn new(location: &str, code: StatusCode, preserve_path: bool) -> Self {
+    Self {
+      location: parse_template(location),
+      code,
+      preserve_path,
+    }
+  }
+
+  fn location(&self, req: &Request<Body>) -> String {
+    let mut location = String::new();
+
+    for part in &self.location {
+      match part {
+        Part::Literal(literal) => location.push_str(literal),
+        Part::Scheme => {
+          let tls = req
+            .extensions()
+            .get::<ConnInfo>()
+            .map(|conn_info| conn_info.tls)
+            .unwrap_or(false);
+
+          location.push_str(if tls { "https" } else { "http" })
+        }
+        Part::Host => location.push_str(request_host(req).unwrap_or_default()),
+        Part::Path => match self.preserve_path {
+          true => location.push_str(req.uri().path()),
+          false => location.push('/'),
+        },
+        Part::Query => {
+          if let (true, Some(query)) = (self.preserve_path, req.uri().query()) {
+            location.push('?');
+            location.push_str(query);
+          }
+        }
+      }
+    }
+
+    location
+  }
+}
+
+#[async_trait]
+impl Service for RedirectService {
+  async fn handle(&self, req: Request<Body>) -> PuxResult<Response<Body>> {
+    let location = HeaderValue::try_from(self.location(&req)).map_err(http::Error::from)?;
+
+    Ok(
+      Response::builder()
+        .status(self.code)
+        .header(LOCATION, location)
+        .body(Body::empty())?,
+    )
+  }
+}
+
+fn parse_template(template: &str) -> Vec<Part> {
+  let mut parts = Vec::new();
+  let mut rest = template;
+
+  while let Some(start) = rest.find('{') {
+    let placeholder = rest[start..].find('}').and_then(|end| {
+      let part = match &rest[start + 1..start + end] {
+        "scheme" => Part::Scheme,
+        "host" => Part::Host,
+        "path" => Part::Path,
+        "query" => Part::Query,
+        _ => return None,
+      };
+      Some((part, start + end + 1))
+    });
+
+    match placeholder {
+      Some((part, end)) => {
+        if start > 0 {
+          parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        parts.push(part);
+        rest = &rest[end..];
+      }
+      None => {
+        parts.push(Part::Literal(rest[..=start].to_string()));
+        rest = &rest[start + 1..];
+      }
+    }
+  }
+
+  if !rest.is_empty() {
+    parts.push(Part::Literal(rest.to_string()));
+  }
+
+  parts
+}
@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use hyper::http::HeaderMap;
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::service::Service;
+use crate::PuxResult;
+
+pub(crate) struct RespondService {
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes,
+}
+
+impl RespondService {
+  pub(crate) fn new(status: StatusCode, headers: HeaderMap, body: Bytes) -> Self {
+    Self {
+      status,
+      headers,
+      body,
+    }
+  }
+}
+
+#[async_trait]
+impl Service for RespondService {
+  async fn handle(&self, _req: Request<Body>) -> PuxResult<Response<Body>> {
+    let mut resp = Response::new(Body::from(self.body.clone()));
+    *resp.status_mut() = self.status;
+    *resp.headers_mut() = self.headers.clone();
+    Ok(resp)
+  }
+}
@@ -0,0 +1,94 @@
+use hyper::header::HeaderName;
+use hyper::http::HeaderValue;
+use hyper::HeaderMap;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::OpenTelemetryConfig;
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+/// Exports spans via otlp/http and propagates the w3c `traceparent` and `tracestate` headers.
+pub(crate) fn layer<S>(config: &OpenTelemetryConfig) -> OpenTelemetryLayer<S, Tracer>
+where
+  S: Subscriber + for<'span> LookupSpan<'span>,
+{
+  global::set_text_map_propagator(TraceContextPropagator::new());
+
+  let ratio = Sampler::TraceIdRatioBased(config.sample_ratio);
+  let sampler = if config.parent_based {
+    Sampler::ParentBased(Box::new(ratio))
+  } else {
+    ratio
+  };
+
+  let tracer = opentelemetry_otlp::new_pipeline()
+    .tracing()
+    .with_exporter(
+      opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.endpoint),
+    )
+    .with_trace_config(
+      trace::config()
+        .with_sampler(sampler)
+        .with_resource(Resource::new(vec![KeyValue::new(
+          "service.name",
+          config.service_name.clone(),
+        )])),
+    )
+    .install_batch(runtime::Tokio)
+    .expect("failed to install opentelemetry exporter");
+
+  tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Flushes the spans that have not been exported yet.
+pub(crate) fn shutdown() {
+  global::shutdown_tracer_provider();
+}
+
+/// Continues the trace of the client, if it sent a `traceparent`.
+pub(crate) fn set_parent(span: &Span, headers: &HeaderMap) {
+  let parent =
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+  span.set_parent(parent);
+}
+
+/// Replaces the trace headers with ones pointing at the span.
+pub(crate) fn inject(span: &Span, headers: &mut HeaderMap) {
+  let context: Context = span.context();
+  global::get_text_map_propagator(|propagator| {
+    propagator.inject_context(&context, &mut HeaderInjector(headers))
+  });
+}
+
+impl Extractor for HeaderExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|value| value.to_str().ok())
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(HeaderName::as_str).collect()
+  }
+}
+
+impl Injector for HeaderInjector<'_> {
+  fn set(&mut self, key: &str, value: String) {
+    if let (Ok(name), Ok(value)) = (
+      HeaderName::from_bytes(key.as_bytes()),
+      HeaderValue::try_from(value),
+    ) {
+      self.0.insert(name, value);
+    }
+  }
+}
@@ -0,0 +1,25 @@
+/// Replaces `{name}` placeholders in a single pass, so substituted values are never interpreted
+/// as placeholders. `lookup` appends the value of a name and returns `false` for unknown names,
+/// which are kept as they are.
+pub fn render(template: &str, mut lookup: impl FnMut(&str, &mut String) -> bool) -> String {
+  let mut rendered = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    rendered.push_str(&rest[..start]);
+    let placeholder = &rest[start..];
+    let end = match placeholder.find('}') {
+      Some(end) => end,
+      None => {
+        rest = placeholder;
+        break;
+      }
+    };
+
+    if !lookup(&placeholder[1..end], &mut rendered) {
+      rendered.push_str(&placeholder[..=end]);
+    }
+    rest = &placeholder[end + 1..];
+  }
+  rendered.push_str(rest);
+  rendered
+}
@@ -16,7 +16,7 @@ use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
 use tokio_rustls::TlsConnector;
-use tracing::error;
+use tracing::{error, info_span, Instrument};
 
 use crate::upstream::error::Error;
 
@@ -42,6 +42,7 @@ enum Connection {
 
 pub(crate) struct HttpConnection {
   send: SendRequest<Body>,
+  addr: SocketAddr,
 }
 
 impl Connection {
@@ -49,7 +50,10 @@ impl Connection {
     addr: &SocketAddr,
     sni: &Option<ServerName>,
   ) -> Result<Connection, Error> {
-    let stream = match TcpStream::connect(addr).await {
+    let stream = match TcpStream::connect(addr)
+      .instrument(info_span!("tcp_connect"))
+      .await
+    {
       Ok(stream) => stream,
       Err(err) => return Err(Error::Connect(err)),
     };
@@ -60,7 +64,11 @@ impl Connection {
 
     match sni {
       None => Ok(Self::Raw(Box::new(stream))),
-      Some(name) => match TLS_CONNECTOR.connect(name.clone(), stream).await {
+      Some(name) => match TLS_CONNECTOR
+        .connect(name.clone(), stream)
+        .instrument(info_span!("tls_handshake"))
+        .await
+      {
         Ok(tls_stream) => Ok(Self::Tls(Box::new(tls_stream))),
         Err(err) => Err(Error::Tls(err)),
       },
@@ -70,7 +78,13 @@ impl Connection {
 
 impl HttpConnection {
   pub(crate) async fn open(addr: &SocketAddr, sni: &Option<ServerName>) -> Result<Self, Error> {
-    let conn = Connection::open(addr, sni).await?;
+    let conn = Connection::open(addr, sni)
+      .instrument(info_span!(
+        "connect",
+        server.address = %addr.ip(),
+        server.port = addr.port()
+      ))
+      .await?;
 
     let (send, conn) = match Builder::new().handshake(conn).await {
       Ok(data) => data,
@@ -83,7 +97,11 @@ impl HttpConnection {
       }
     });
 
-    Ok(Self { send })
+    Ok(Self { send, addr: *addr })
+  }
+
+  pub(crate) fn addr(&self) -> SocketAddr {
+    self.addr
   }
 
   pub(crate) async fn ready(&mut self) -> hyper::Result<()> {
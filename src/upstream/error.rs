@@ -7,6 +7,8 @@ pub(crate) enum Error {
   HttpHandshake(hyper::Error),
   Other(io::Error),
   Forward(hyper::Error),
+  /// Every member is draining, disabled or has no weight.
+  NoMember,
 }
 
 impl Debug for Error {
@@ -17,6 +19,7 @@ impl Debug for Error {
       Self::HttpHandshake(inner) => write!(f, "Error while doing http initialization: {:?}", inner),
       Self::Other(inner) => write!(f, "Unknown error: {:?}", inner),
       Self::Forward(inner) => write!(f, "Unable to forward request: {:?}", inner),
+      Self::NoMember => write!(f, "No member is receiving requests"),
     }
   }
 }
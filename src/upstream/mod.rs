@@ -1,27 +1,131 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use hyper::{Body, Request, Response};
+use hyper::header::HOST;
+use hyper::http::uri::PathAndQuery;
+use hyper::http::HeaderValue;
+use hyper::{http, Body, Request, Response, StatusCode, Uri, Version};
 use tokio_rustls::rustls::ServerName;
+use tracing::warn;
 
+use crate::limiter::Limiter;
+use crate::upstream::error::Error;
 use crate::upstream::pool::HttpPool;
+pub(crate) use crate::upstream::pool::{MemberState, PoolStats};
 use crate::PuxResult;
 
 mod conn;
 mod error;
 mod pool;
 
+pub(crate) enum HostHeader {
+  Preserve,
+  Fixed(HeaderValue),
+}
+
+/// Attached to upstream responses, the member that answered and how long it took to respond.
+#[derive(Clone, Copy)]
+pub(crate) struct UpstreamInfo {
+  pub(crate) addr: SocketAddr,
+  pub(crate) latency: Duration,
+}
+
 pub(crate) struct Upstream {
   pool: HttpPool,
+  host_header: HostHeader,
+  limiter: Option<Arc<Limiter>>,
 }
 
 impl Upstream {
-  pub(crate) async fn new(addrs: Vec<SocketAddr>, sni: Option<ServerName>) -> Self {
+  pub(crate) async fn new(
+    members: Vec<(SocketAddr, u32, MemberState)>,
+    sni: Option<ServerName>,
+    host_header: HostHeader,
+    limiter: Option<Arc<Limiter>>,
+  ) -> Self {
     Self {
-      pool: HttpPool::new(addrs, sni),
+      pool: HttpPool::new(members, sni),
+      host_header,
+      limiter,
+    }
+  }
+
+  pub(crate) async fn send(&self, mut req: Request<Body>) -> PuxResult<Response<Body>> {
+    self.normalize(&mut req)?;
+
+    let permit = match &self.limiter {
+      Some(limiter) => match limiter.acquire().await {
+        Some(permit) => Some(permit),
+        None => return Err(StatusCode::SERVICE_UNAVAILABLE.into()),
+      },
+      None => None,
+    };
+
+    let mut result = self.pool.send(req).await;
+    if let Some(mut permit) = permit {
+      permit.complete(matches!(&result, Ok(resp) if !resp.status().is_server_error()));
+      result = result.map(|resp| permit.attach(resp));
+    }
+
+    match result {
+      Ok(resp) => Ok(resp),
+      Err(Error::NoMember) => {
+        warn!("Upstream request failed: {:?}", Error::NoMember);
+        Err(StatusCode::SERVICE_UNAVAILABLE.into())
+      }
+      Err(err) => {
+        warn!("Upstream request failed: {:?}", err);
+        Err(StatusCode::BAD_GATEWAY.into())
+      }
     }
   }
 
-  pub(crate) async fn send(&self, req: Request<Body>) -> PuxResult<Response<Body>> {
-    Ok(self.pool.send(req).await.unwrap())
+  pub(crate) async fn pool_stats(&self) -> Vec<PoolStats> {
+    self.pool.stats().await
+  }
+
+  pub(crate) async fn update_member(
+    &self,
+    addr: SocketAddr,
+    state: Option<MemberState>,
+    weight: Option<u32>,
+  ) -> bool {
+    self.pool.update_member(addr, state, weight).await
+  }
+
+  pub(crate) async fn add_member(&self, addr: SocketAddr, weight: u32) -> bool {
+    self.pool.add_member(addr, weight).await
+  }
+
+  pub(crate) async fn remove_member(&self, addr: SocketAddr) -> bool {
+    self.pool.remove_member(addr).await
+  }
+
+  /// Converts the request uri into origin-form and sets the host header according to the
+  /// configured policy, http2 requests only carry the host in their (absolute) uri.
+  fn normalize(&self, req: &mut Request<Body>) -> PuxResult<()> {
+    let host = match &self.host_header {
+      HostHeader::Fixed(host) => host.clone(),
+      HostHeader::Preserve => match (req.headers().get(HOST), req.uri().authority()) {
+        (Some(host), _) => host.clone(),
+        (None, Some(authority)) => {
+          HeaderValue::from_str(authority.as_str()).map_err(http::Error::from)?
+        }
+        (None, None) => return Err(StatusCode::BAD_REQUEST.into()),
+      },
+    };
+
+    let path_and_query = req
+      .uri()
+      .path_and_query()
+      .cloned()
+      .unwrap_or_else(|| PathAndQuery::from_static("/"));
+
+    *req.uri_mut() = Uri::from(path_and_query);
+    *req.version_mut() = Version::HTTP_11;
+    req.headers_mut().insert(HOST, host);
+
+    Ok(())
   }
 }
@@ -7,10 +7,14 @@ use hyper::{Body, Request, Response};
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 use tokio_rustls::rustls::ServerName;
-use tracing::error;
+use tracing::field::Empty;
+use tracing::{error, info_span, Instrument, Span};
 
+use crate::metrics::METRICS;
+use crate::telemetry;
 use crate::upstream::conn::HttpConnection;
 use crate::upstream::error::Error;
+use crate::upstream::UpstreamInfo;
 
 pub(crate) struct HttpPool {
   internal: Arc<Mutex<Internal>>,
@@ -19,32 +23,65 @@ pub(crate) struct HttpPool {
 
 struct Internal {
   // todo: use concurrent hash map: https://docs.rs/flurry
-  conns: HashMap<SocketAddr, Vec<Instant>>,
+  members: HashMap<SocketAddr, Member>,
   idle: Vec<Entry>,
   force_use: Duration,
 }
 
+struct Member {
+  conns: Vec<Instant>,
+  /// Consecutive failed connects or requests, reset by the next response.
+  failures: u32,
+  last_error: Option<String>,
+  state: MemberState,
+  /// Relative share of new connections, `0` receives none.
+  weight: u32,
+  /// Added at runtime instead of by the configuration.
+  temporary: bool,
+}
+
+/// Whether a member receives new requests. Requests in flight are finished in every state, idle
+/// connections of members not receiving requests are closed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemberState {
+  Active,
+  /// Taken out of rotation, e.g. during a deploy.
+  Draining,
+  Disabled,
+}
+
 struct Entry {
   idle_since: Instant,
   id: Instant,
   conn: HttpConnection,
 }
 
+/// Connections to and health of one address of the pool.
+pub(crate) struct PoolStats {
+  pub(crate) addr: SocketAddr,
+  pub(crate) idle: usize,
+  pub(crate) active: usize,
+  pub(crate) failures: u32,
+  pub(crate) last_error: Option<String>,
+  pub(crate) state: MemberState,
+  pub(crate) weight: u32,
+  pub(crate) temporary: bool,
+}
+
 enum SelectResult {
   Conn(HttpConnection),
   Addr(SocketAddr),
 }
 
 impl HttpPool {
-  pub(crate) fn new(addrs: Vec<SocketAddr>, sni: Option<ServerName>) -> Self {
-    let mut conns = HashMap::with_capacity(addrs.len());
-
-    for addr in addrs {
-      conns.insert(addr, vec![]);
-    }
+  pub(crate) fn new(members: Vec<(SocketAddr, u32, MemberState)>, sni: Option<ServerName>) -> Self {
+    let members = members
+      .into_iter()
+      .map(|(addr, weight, state)| (addr, Member::new(state, weight, false)))
+      .collect();
 
     let internal = Arc::new(Mutex::new(Internal {
-      conns,
+      members,
       idle: vec![],
       force_use: Duration::from_millis(10),
     }));
@@ -61,12 +98,41 @@ impl HttpPool {
     Self { internal, sni }
   }
 
-  pub(crate) async fn send(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
+  pub(crate) async fn send(&self, mut req: Request<Body>) -> Result<Response<Body>, Error> {
+    let span = info_span!(
+      "upstream",
+      otel.kind = "client",
+      otel.name = req.method().as_str(),
+      otel.status_code = Empty,
+      http.request.method = req.method().as_str(),
+      server.address = Empty,
+      server.port = Empty,
+      http.response.status_code = Empty,
+    );
+    telemetry::inject(&span, req.headers_mut());
+
+    let result = self.dispatch(req, &span).instrument(span.clone()).await;
+    match &result {
+      Ok(resp) => {
+        span.record("http.response.status_code", resp.status().as_u16());
+        if resp.status().is_server_error() {
+          span.record("otel.status_code", "ERROR");
+        }
+      }
+      Err(_) => {
+        span.record("otel.status_code", "ERROR");
+      }
+    }
+    result
+  }
+
+  async fn dispatch(&self, req: Request<Body>, span: &Span) -> Result<Response<Body>, Error> {
+    let start = Instant::now();
     let (id, result) = {
       let mut internal = self.internal.lock().await;
       match internal.select() {
         None => {
-          let (id, addr) = internal.select_addr();
+          let (id, addr) = internal.select_addr().ok_or(Error::NoMember)?;
           (id, SelectResult::Addr(addr))
         }
         Some((id, conn)) => (id, SelectResult::Conn(conn)),
@@ -78,13 +144,38 @@ impl HttpPool {
       SelectResult::Addr(addr) => match HttpConnection::open(&addr, &self.sni).await {
         Ok(conn) => conn,
         Err(err) => {
-          self.internal.lock().await.remove_conn(&id);
+          METRICS
+            .upstream_connect_errors
+            .with_label_values(&[&addr.to_string()])
+            .inc();
+          let mut internal = self.internal.lock().await;
+          internal.remove_conn(&id);
+          internal.record(addr, Some(&err));
           return Err(err);
         }
       },
     };
 
-    let resp = conn.send(req).await;
+    span.record("server.address", conn.addr().ip().to_string());
+    span.record("server.port", conn.addr().port());
+
+    let resp = conn.send(req).await.map(|mut resp| {
+      let latency = start.elapsed();
+      METRICS
+        .upstream_duration
+        .with_label_values(&[&conn.addr().to_string()])
+        .observe(latency.as_secs_f64());
+      resp.extensions_mut().insert(UpstreamInfo {
+        addr: conn.addr(),
+        latency,
+      });
+      resp
+    });
+    self
+      .internal
+      .lock()
+      .await
+      .record(conn.addr(), resp.as_ref().err());
 
     let internal_clone = self.internal.clone();
     tokio::spawn(async move {
@@ -98,62 +189,209 @@ impl HttpPool {
 
     resp
   }
+
+  pub(crate) async fn stats(&self) -> Vec<PoolStats> {
+    let internal = self.internal.lock().await;
+    internal
+      .members
+      .iter()
+      .map(|(addr, member)| {
+        let idle = internal
+          .idle
+          .iter()
+          .filter(|entry| entry.conn.addr() == *addr)
+          .count();
+        PoolStats {
+          addr: *addr,
+          idle,
+          active: member.conns.len().saturating_sub(idle),
+          failures: member.failures,
+          last_error: member.last_error.clone(),
+          state: member.state,
+          weight: member.weight,
+          temporary: member.temporary,
+        }
+      })
+      .collect()
+  }
+
+  /// Changes the state or weight of a member, `false` if there is no member with the address.
+  pub(crate) async fn update_member(
+    &self,
+    addr: SocketAddr,
+    state: Option<MemberState>,
+    weight: Option<u32>,
+  ) -> bool {
+    let mut internal = self.internal.lock().await;
+    let member = match internal.members.get_mut(&addr) {
+      Some(member) => member,
+      None => return false,
+    };
+
+    if let Some(state) = state {
+      member.state = state;
+    }
+    if let Some(weight) = weight {
+      member.weight = weight;
+    }
+    internal.close_unselectable();
+    true
+  }
+
+  /// Adds a temporary member, `false` if the address is already a member.
+  pub(crate) async fn add_member(&self, addr: SocketAddr, weight: u32) -> bool {
+    let mut internal = self.internal.lock().await;
+    if internal.members.contains_key(&addr) {
+      return false;
+    }
+
+    internal
+      .members
+      .insert(addr, Member::new(MemberState::Active, weight, true));
+    true
+  }
+
+  /// Removes a temporary member, requests in flight to it are still finished.
+  pub(crate) async fn remove_member(&self, addr: SocketAddr) -> bool {
+    let mut internal = self.internal.lock().await;
+    if !internal
+      .members
+      .get(&addr)
+      .is_some_and(|member| member.temporary)
+    {
+      return false;
+    }
+
+    internal.members.remove(&addr);
+    internal.close_unselectable();
+    true
+  }
+}
+
+impl Member {
+  fn new(state: MemberState, weight: u32, temporary: bool) -> Self {
+    Self {
+      conns: vec![],
+      failures: 0,
+      last_error: None,
+      state,
+      weight,
+      temporary,
+    }
+  }
+
+  fn selectable(&self) -> bool {
+    self.state == MemberState::Active && self.weight > 0
+  }
+}
+
+impl MemberState {
+  pub(crate) fn parse(raw: &str) -> Option<Self> {
+    match raw {
+      "active" => Some(Self::Active),
+      "draining" => Some(Self::Draining),
+      "disabled" => Some(Self::Disabled),
+      _ => None,
+    }
+  }
+
+  pub(crate) fn as_str(&self) -> &'static str {
+    match self {
+      Self::Active => "active",
+      Self::Draining => "draining",
+      Self::Disabled => "disabled",
+    }
+  }
 }
 
 impl Internal {
+  /// Reuses an idle connection of the member that should receive the next request.
   fn select(&mut self) -> Option<(Instant, HttpConnection)> {
-    let mut candidate = None;
+    let target = self.target()?;
+    let mut candidate: Option<(usize, Instant)> = None;
 
     let force_use = Instant::now() - self.force_use;
 
-    for (i, entry) in self.idle.iter().rev().enumerate() {
+    for (i, entry) in self.idle.iter().enumerate().rev() {
+      if entry.conn.addr() != target {
+        continue;
+      }
+
       if force_use >= entry.idle_since {
-        candidate = Some((i, &entry.idle_since));
+        candidate = Some((i, entry.idle_since));
         break;
       }
 
       match candidate {
-        None => candidate = Some((i, &entry.idle_since)),
+        None => candidate = Some((i, entry.idle_since)),
         Some((_, best_idle_since)) => {
-          if best_idle_since > &entry.idle_since {
-            candidate = Some((i, &entry.idle_since))
+          if best_idle_since > entry.idle_since {
+            candidate = Some((i, entry.idle_since))
           }
         }
       }
     }
 
-    // drop reference &Instant from Option<(usize, &Instant)> in candidate
-    let candidate = candidate.map(|(i, _)| i);
-
-    candidate.map(|i| {
+    candidate.map(|(i, _)| {
       let entry = self.idle.remove(i);
       (entry.id, entry.conn)
     })
   }
 
-  fn select_addr(&mut self) -> (Instant, SocketAddr) {
-    let mut candidate = None;
+  /// The member with the fewest busy connections relative to its weight, on a tie the one with
+  /// idle connections to reuse.
+  fn target(&self) -> Option<SocketAddr> {
+    let mut idle: HashMap<SocketAddr, usize> = HashMap::new();
+    for entry in &self.idle {
+      *idle.entry(entry.conn.addr()).or_default() += 1;
+    }
+
+    let mut candidate: Option<(&SocketAddr, u64, u32, bool)> = None;
+
+    for (addr, member) in &self.members {
+      if !member.selectable() {
+        continue;
+      }
+
+      let idle = idle.get(addr).copied().unwrap_or_default();
+      let busy = member.conns.len().saturating_sub(idle) as u64;
 
-    for (addr, conns) in &self.conns {
       match candidate {
-        None => candidate = Some((addr, conns.len())),
-        Some((_, low_conns)) => {
-          if conns.len() < low_conns {
-            candidate = Some((addr, conns.len()))
+        None => candidate = Some((addr, busy, member.weight, idle > 0)),
+        Some((_, low_busy, low_weight, low_idle)) => {
+          // compares busy / weight without dividing
+          let lhs = busy * u64::from(low_weight);
+          let rhs = low_busy * u64::from(member.weight);
+          if lhs < rhs || (lhs == rhs && idle > 0 && !low_idle) {
+            candidate = Some((addr, busy, member.weight, idle > 0))
           }
         }
       }
     }
 
-    let candidate = *candidate.expect("No addresses for upstream provided").0;
+    candidate.map(|(addr, ..)| *addr)
+  }
+
+  /// Registers a new connection to the member that should receive the next request.
+  fn select_addr(&mut self) -> Option<(Instant, SocketAddr)> {
+    let candidate = self.target()?;
     let id = Instant::now();
 
-    self.conns.get_mut(&candidate).unwrap().push(id);
+    self.members.get_mut(&candidate).unwrap().conns.push(id);
 
-    (id, candidate)
+    Some((id, candidate))
   }
 
   fn push(&mut self, id: Instant, conn: HttpConnection) {
+    let selectable = self
+      .members
+      .get(&conn.addr())
+      .is_some_and(Member::selectable);
+    if !selectable {
+      self.remove_conn(&id);
+      return;
+    }
+
     self.idle.push(Entry {
       idle_since: Instant::now(),
       id,
@@ -162,8 +400,38 @@ impl Internal {
   }
 
   fn remove_conn(&mut self, id: &Instant) {
-    for conns in self.conns.values_mut() {
-      conns.retain(|c_id| c_id != id)
+    for member in self.members.values_mut() {
+      member.conns.retain(|c_id| c_id != id)
+    }
+  }
+
+  fn record(&mut self, addr: SocketAddr, err: Option<&Error>) {
+    if let Some(member) = self.members.get_mut(&addr) {
+      match err {
+        Some(err) => {
+          member.failures += 1;
+          member.last_error = Some(format!("{:?}", err));
+        }
+        None => member.failures = 0,
+      }
+    }
+  }
+
+  /// Closes the idle connections of members that no longer receive requests.
+  fn close_unselectable(&mut self) {
+    let members = &self.members;
+    let (keep, close): (Vec<_>, Vec<_>) =
+      std::mem::take(&mut self.idle)
+        .into_iter()
+        .partition(|entry| {
+          members
+            .get(&entry.conn.addr())
+            .is_some_and(Member::selectable)
+        });
+
+    self.idle = keep;
+    for entry in close {
+      self.remove_conn(&entry.id);
     }
   }
 
@@ -184,3 +452,150 @@ impl Internal {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  fn internal(members: &[(&str, u32, MemberState)]) -> Internal {
+    Internal {
+      members: members
+        .iter()
+        .map(|(addr, weight, state)| (addr.parse().unwrap(), Member::new(*state, *weight, false)))
+        .collect(),
+      idle: vec![],
+      force_use: Duration::from_millis(10),
+    }
+  }
+
+  /// Selected addresses, connections stay open like for long running requests.
+  fn select(internal: &mut Internal, count: usize) -> HashMap<SocketAddr, usize> {
+    let mut selected = HashMap::new();
+    for _ in 0..count {
+      let (_, addr) = internal.select_addr().unwrap();
+      *selected.entry(addr).or_default() += 1;
+    }
+    selected
+  }
+
+  fn addr(raw: &str) -> SocketAddr {
+    raw.parse().unwrap()
+  }
+
+  #[test]
+  fn spreads_by_weight() {
+    let mut internal = internal(&[
+      ("127.0.0.1:1", 3, MemberState::Active),
+      ("127.0.0.1:2", 1, MemberState::Active),
+    ]);
+    let selected = select(&mut internal, 8);
+    assert_eq!(selected[&addr("127.0.0.1:1")], 6);
+    assert_eq!(selected[&addr("127.0.0.1:2")], 2);
+  }
+
+  #[test]
+  fn skips_draining_disabled_and_zero_weight() {
+    let mut internal = internal(&[
+      ("127.0.0.1:1", 1, MemberState::Active),
+      ("127.0.0.1:2", 5, MemberState::Draining),
+      ("127.0.0.1:3", 5, MemberState::Disabled),
+      ("127.0.0.1:4", 0, MemberState::Active),
+    ]);
+    let selected = select(&mut internal, 4);
+    assert_eq!(selected.len(), 1);
+    assert_eq!(selected[&addr("127.0.0.1:1")], 4);
+  }
+
+  #[test]
+  fn nothing_selectable() {
+    let mut internal = internal(&[
+      ("127.0.0.1:1", 1, MemberState::Draining),
+      ("127.0.0.1:2", 1, MemberState::Disabled),
+    ]);
+    assert!(internal.select_addr().is_none());
+  }
+
+  #[test]
+  fn prefers_members_with_fewer_connections() {
+    let mut internal = internal(&[
+      ("127.0.0.1:1", 1, MemberState::Active),
+      ("127.0.0.1:2", 1, MemberState::Active),
+    ]);
+    let (id, first) = internal.select_addr().unwrap();
+    let (_, second) = internal.select_addr().unwrap();
+    assert_ne!(first, second);
+
+    // the first finished, so it has fewer connections now
+    internal.remove_conn(&id);
+    assert_eq!(internal.select_addr().unwrap().1, first);
+  }
+
+  /// Adds an idle connection to the listener, registered like a finished request.
+  async fn idle(internal: &mut Internal, listener: &TcpListener, idle_for: Duration) -> Instant {
+    let addr = listener.local_addr().unwrap();
+    let conn = HttpConnection::open(&addr, &None).await.unwrap();
+    let id = Instant::now();
+    internal.members.get_mut(&addr).unwrap().conns.push(id);
+    internal.idle.push(Entry {
+      idle_since: Instant::now() - idle_for,
+      id,
+      conn,
+    });
+    id
+  }
+
+  #[tokio::test]
+  async fn reuses_the_selected_idle_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut internal = internal(&[(
+      &listener.local_addr().unwrap().to_string(),
+      1,
+      MemberState::Active,
+    )]);
+
+    let old = idle(&mut internal, &listener, Duration::from_secs(1)).await;
+    let recent = idle(&mut internal, &listener, Duration::ZERO).await;
+
+    // the recent one might still be in use by hyper, so the one idle long enough wins
+    assert_eq!(internal.select().unwrap().0, old);
+    assert_eq!(internal.idle.len(), 1);
+    assert_eq!(internal.idle[0].id, recent);
+    assert_eq!(internal.select().unwrap().0, recent);
+    assert!(internal.select().is_none());
+  }
+
+  #[tokio::test]
+  async fn reuses_idle_connections_by_weight() {
+    let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut internal = internal(&[
+      (
+        &first.local_addr().unwrap().to_string(),
+        1,
+        MemberState::Active,
+      ),
+      (
+        &second.local_addr().unwrap().to_string(),
+        1,
+        MemberState::Active,
+      ),
+    ]);
+
+    // the first member still has a busy connection
+    internal
+      .members
+      .get_mut(&first.local_addr().unwrap())
+      .unwrap()
+      .conns
+      .push(Instant::now());
+    let first_idle = idle(&mut internal, &first, Duration::from_secs(1)).await;
+    let second_idle = idle(&mut internal, &second, Duration::from_secs(1)).await;
+
+    assert_eq!(internal.select().unwrap().0, second_idle);
+    // both have a busy connection, the idle one is reused instead of opening a new one
+    assert_eq!(internal.select().unwrap().0, first_idle);
+    assert!(internal.select().is_none());
+  }
+}